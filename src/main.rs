@@ -1,16 +1,25 @@
 use clap::{Parser, Subcommand};
+use rand::distributions::{Distribution, WeightedIndex};
+use rand::rngs::StdRng;
 use rand::seq::SliceRandom;
-use regex::Regex;
-use std::collections::HashSet;
+use rand::{Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
 use std::fs;
-use std::io::{BufRead, BufReader, Write};
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
 use std::net::TcpStream;
-use std::path::Path;
+use std::path::PathBuf;
 use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
 
 #[derive(Parser)]
 #[command(name = "music_selection")]
 #[command(about = "Music selection tool")]
+#[command(
+    after_help = "Can also run as a rofi script mode:\n  rofi -show music -modi music:mpd_rofi -kb-custom-1 Ctrl+Return"
+)]
 struct Cli {
     #[arg(long, help = "Pre-select artist")]
     artist: Option<String>,
@@ -21,6 +30,52 @@ struct Cli {
     #[arg(long, default_value = "0", help = "Pre-select song index")]
     preselect: usize,
 
+    #[arg(
+        long,
+        help = "Show album covers as icons in menus (MPD_ROFI_SHOW_ICONS=1 in rofi script mode)"
+    )]
+    show_icons: bool,
+
+    #[arg(long, value_enum, default_value_t, help = "Order of the album menu")]
+    sort: AlbumSort,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value_t,
+        help = "Order of the quarantine and list menus"
+    )]
+    list_sort: ListSort,
+
+    #[arg(
+        long,
+        help = "Notification summary template (%artist%, %album%, %title%)"
+    )]
+    notify_summary: Option<String>,
+
+    #[arg(long, help = "Notification body template (%artist%, %album%, %title%)")]
+    notify_body: Option<String>,
+
+    #[arg(long, value_enum, default_value_t, help = "How to show notifications")]
+    notify_backend: NotificationBackend,
+
+    #[arg(long, value_enum, default_value_t, help = "Notification urgency")]
+    notify_urgency: NotificationUrgency,
+
+    #[arg(
+        long,
+        help = "Add Next/Queue album/Quarantine buttons to notifications (D-Bus only)"
+    )]
+    notify_actions: bool,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value_t,
+        help = "Format of messages on stdout"
+    )]
+    output: OutputMode,
+
     #[command(subcommand)]
     command: Option<Commands>,
 }
@@ -30,17 +85,348 @@ enum Commands {
     #[command(about = "Select artist then album then song")]
     Artist,
     #[command(about = "Select album then song")]
-    Album,
+    Album {
+        #[arg(long, value_parser = clap::value_parser!(u8).range(0..=5), help = "Only show albums rated at least this many stars")]
+        min_rating: Option<u8>,
+    },
     #[command(about = "Select song from all songs")]
-    Song,
-    #[command(about = "Play a random album without prompts")]
-    Random,
-    #[command(about = "Select album from quarantine list")]
-    Quarantine,
+    Song {
+        #[arg(long, value_parser = clap::value_parser!(u8).range(0..=5), help = "Only show songs rated at least this many stars")]
+        min_rating: Option<u8>,
+    },
+    #[command(about = "Play random albums or songs without prompts")]
+    Random {
+        #[arg(long, value_parser = clap::value_parser!(u8).range(0..=5), help = "Only pick albums rated at least this many stars")]
+        min_rating: Option<u8>,
+
+        #[command(flatten)]
+        options: RandomOptions,
+    },
+    #[command(about = "Select album from quarantine list, or manage the list")]
+    Quarantine {
+        #[command(subcommand)]
+        action: Option<ListAction>,
+    },
     #[command(about = "Play a random quarantine album without prompts")]
     RandomQuarantine,
+    #[command(about = "Select album from a named list, or manage the list")]
+    List {
+        #[arg(help = "List name, e.g. to-review")]
+        name: String,
+        #[command(subcommand)]
+        action: Option<ListAction>,
+    },
+    #[command(about = "Play a random album from a named list without prompts")]
+    RandomList {
+        #[arg(help = "List name")]
+        name: String,
+    },
     #[command(about = "Show current playlist and jump to selected song")]
     Playlist,
+    #[command(about = "Browse the music directory")]
+    Browse,
+    #[command(about = "Load, append, save or delete stored playlists")]
+    Playlists {
+        #[arg(long, help = "Show playlist management actions instead of loading")]
+        manage: bool,
+    },
+    #[command(about = "Rate the current song with 0 to 5 stars")]
+    Rate {
+        #[arg(long, help = "Rate all songs of the current album")]
+        album: bool,
+    },
+    #[command(about = "Run in the background and react to MPD events")]
+    Daemon {
+        #[arg(
+            long,
+            default_value_t = 3,
+            help = "Offer to graduate a quarantine album after this many complete listens (0 to disable)"
+        )]
+        graduate_after: usize,
+
+        #[arg(
+            long,
+            help = "Add new albums to quarantine whenever the database changes"
+        )]
+        import_new: bool,
+    },
+    #[command(name = "autodj", about = "Keep the queue topped up with random music")]
+    AutoDj {
+        #[arg(
+            long,
+            default_value = "3",
+            help = "Add music when fewer songs than this remain after the current one"
+        )]
+        threshold: usize,
+
+        #[arg(
+            long,
+            default_value = "library",
+            value_parser = parse_autodj_source,
+            help = "library, quarantine, list:NAME, genre:NAME or playlist:NAME"
+        )]
+        source: AutoDjSource,
+
+        #[command(flatten)]
+        options: RandomOptions,
+    },
+    #[command(about = "Select from recently played albums and songs")]
+    History {
+        #[arg(
+            long,
+            default_value = "50",
+            help = "Number of albums and songs to show"
+        )]
+        limit: usize,
+    },
+    #[command(about = "Play an album or song by name without opening rofi")]
+    Play {
+        #[command(flatten)]
+        target: PlayTarget,
+    },
+    #[command(about = "Queue an album or song by name without opening rofi")]
+    Queue {
+        #[command(flatten)]
+        target: PlayTarget,
+    },
+    #[command(about = "Print artists, albums or songs without opening rofi")]
+    Ls {
+        #[command(subcommand)]
+        target: LsTarget,
+    },
+    #[command(about = "Search songs and albums with a free-text query")]
+    Search {
+        #[arg(
+            help = "Query such as 'artist:foo album:\"bar baz\" year:1990' (prompts if omitted)"
+        )]
+        query: Option<String>,
+    },
+}
+
+const MPD_ADDRESS: &str = "localhost:6600";
+
+/// The list used by the `quarantine` commands
+const QUARANTINE_LIST: &str = "quarantine";
+
+type AlbumSelection = (String, String, bool);
+
+#[derive(Subcommand)]
+enum LsTarget {
+    #[command(about = "Print all album artists")]
+    Artists {
+        #[arg(
+            short,
+            long,
+            default_value = "%artist%",
+            help = "Line template (%artist%)"
+        )]
+        format: String,
+    },
+    #[command(about = "Print albums")]
+    Albums {
+        #[arg(long, help = "Only albums of this album artist")]
+        artist: Option<String>,
+        #[arg(
+            short,
+            long,
+            default_value = "%artist%\\t%album%",
+            help = "Line template (%artist%, %album%)"
+        )]
+        format: String,
+    },
+    #[command(about = "Print songs")]
+    Songs {
+        #[arg(long, help = "Only songs of this album artist")]
+        artist: Option<String>,
+        #[arg(long, requires = "artist", help = "Only songs of this album")]
+        album: Option<String>,
+        #[arg(
+            short,
+            long,
+            default_value = "%artist%\\t%album%\\t%title%",
+            help = "Line template (%artist%, %album%, %title%, %track%, %file%)"
+        )]
+        format: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum ListAction {
+    #[command(about = "Add an album (default: the current album)")]
+    Add {
+        #[arg(long, requires = "album", help = "Album artist")]
+        artist: Option<String>,
+        #[arg(long, requires = "artist", help = "Album")]
+        album: Option<String>,
+    },
+    #[command(about = "Select an album to remove")]
+    Remove,
+    #[command(about = "Print all albums")]
+    List,
+    #[command(about = "Check that every album exists in the MPD library")]
+    Check {
+        #[arg(
+            long,
+            help = "Rewrite entries differing only in case or punctuation to the exact tags"
+        )]
+        fix: bool,
+    },
+    #[command(about = "Add albums added to or changed in the library since the last run")]
+    ImportNew {
+        #[arg(
+            long,
+            help = "Import albums changed since this date (YYYY-MM-DD) instead"
+        )]
+        since: Option<String>,
+    },
+    #[command(about = "Select a listened-to album to remove from the list")]
+    Graduate {
+        #[arg(long, help = "Mark the album's songs with a 'graduated' sticker")]
+        sticker: bool,
+    },
+}
+
+/// Restricts the songs and albums picked from the library.
+#[derive(Debug, Clone, Default, clap::Args)]
+struct LibraryFilter {
+    #[arg(long, help = "Only albums of this album artist")]
+    artist: Option<String>,
+
+    #[arg(long, help = "Only songs of this genre")]
+    genre: Option<String>,
+
+    #[arg(long, help = "Only songs from this year")]
+    year: Option<String>,
+}
+
+impl LibraryFilter {
+    /// Returns the MPD filter expression, or `None` if nothing is filtered.
+    /// Without `starts_with` (before MPD 0.24), the year is matched with a
+    /// regular expression instead.
+    fn expression(&self, starts_with: bool) -> Option<String> {
+        // Dates may carry month and day, so the year only has to be a prefix
+        let year = if starts_with {
+            ("date", "starts_with", self.year.clone())
+        } else {
+            (
+                "date",
+                "=~",
+                self.year.as_ref().map(|year| format!("^{}", year)),
+            )
+        };
+        let terms: Vec<(&str, &str, String)> = [
+            ("albumartist", "==", self.artist.clone()),
+            ("genre", "==", self.genre.clone()),
+            year,
+        ]
+        .into_iter()
+        .filter_map(|(tag, operator, value)| Some((tag, operator, value?)))
+        .collect();
+        filter_expression(&terms)
+    }
+}
+
+#[derive(Debug, Clone, Default, clap::Args)]
+struct RandomOptions {
+    #[arg(long, conflicts_with = "albums", help = "Add this many random songs")]
+    songs: Option<usize>,
+
+    #[arg(long, help = "Add this many random albums")]
+    albums: Option<usize>,
+
+    #[arg(long, help = "Append to the queue instead of replacing it")]
+    append: bool,
+
+    #[command(flatten)]
+    filter: LibraryFilter,
+
+    #[arg(
+        long,
+        help = "Prefer albums that weren't played recently or are rarely played"
+    )]
+    weighted: bool,
+
+    #[arg(long, help = "Never pick albums played within this many days")]
+    exclude_days: Option<u64>,
+
+    #[arg(long, help = "Seed for reproducible picks")]
+    seed: Option<u64>,
+}
+
+/// An album or song named on the command line.
+#[derive(Debug, Clone, clap::Args)]
+struct PlayTarget {
+    #[arg(long, help = "Album artist")]
+    artist: String,
+
+    #[arg(long, help = "Album")]
+    album: String,
+
+    #[arg(long, help = "Song title (default: the whole album)")]
+    title: Option<String>,
+}
+
+/// Total size and the received chunk of a binary MPD response.
+type BinaryChunk = (usize, Vec<u8>);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+enum AlbumSort {
+    #[default]
+    Random,
+    /// Most played first
+    Plays,
+    /// Most recently played first
+    Recent,
+}
+
+/// Order of the album list menus (`quarantine`, `list`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+enum ListSort {
+    /// Order of the list file
+    #[default]
+    File,
+    Random,
+    /// Longest on the list first
+    Oldest,
+    /// Most recently added first
+    Newest,
+    /// Least listened to first
+    FewestListens,
+}
+
+/// Play statistics of an album, aggregated from the stickers of its songs.
+#[derive(Debug, Clone, Default)]
+struct AlbumStats {
+    plays: u32,
+    /// Unix time of the last play, 0 if never played
+    last_played: u64,
+    rating_sum: u32,
+    rated_songs: u32,
+}
+
+impl AlbumStats {
+    /// Average rating of the rated songs of the album.
+    fn rating(&self) -> Option<f64> {
+        (self.rated_songs > 0).then(|| self.rating_sum as f64 / self.rated_songs as f64)
+    }
+}
+
+/// A row printed for rofi in script mode.
+struct ScriptRow {
+    display: String,
+    info: String,
+    icon: Option<PathBuf>,
+    active: bool,
+    urgent: bool,
+}
+
+/// A level of the hierarchical Artist → Album → Song menus.
+#[derive(Debug, Clone)]
+enum Menu {
+    Artist,
+    Album { artist: Option<String> },
+    ListAlbum { name: String },
+    Song { artist: String, album: String },
 }
 
 #[derive(Debug, Clone)]
@@ -52,25 +438,40 @@ struct Track {
     file: String,
 }
 
+#[derive(Debug, Clone)]
+enum DirEntry {
+    Directory(String),
+    File(String),
+}
+
 #[derive(Debug)]
 struct MpdClient {
     stream: TcpStream,
+    /// Protocol version from the greeting, as (major, minor)
+    version: (u32, u32),
 }
 
 impl MpdClient {
     fn connect() -> Result<Self, Box<dyn std::error::Error>> {
-        let stream = TcpStream::connect("localhost:6600")?;
+        let stream = TcpStream::connect(MPD_ADDRESS)?;
 
         // Read initial greeting
         let mut reader = BufReader::new(&stream);
         let mut greeting = String::new();
         reader.read_line(&mut greeting)?;
 
-        if !greeting.starts_with("OK MPD") {
+        let Some(version) = greeting.trim().strip_prefix("OK MPD ") else {
             return Err("Invalid MPD greeting".into());
-        }
+        };
+        let mut numbers = version.split('.').map(|n| n.parse().unwrap_or(0));
+        let version = (numbers.next().unwrap_or(0), numbers.next().unwrap_or(0));
 
-        Ok(MpdClient { stream })
+        Ok(MpdClient { stream, version })
+    }
+
+    /// Whether filter expressions support `starts_with` (MPD 0.24).
+    fn supports_starts_with(&self) -> bool {
+        self.version >= (0, 24)
     }
 
     fn send_command(&mut self, cmd: &str) -> Result<Vec<String>, Box<dyn std::error::Error>> {
@@ -82,7 +483,9 @@ impl MpdClient {
 
         loop {
             line.clear();
-            reader.read_line(&mut line)?;
+            if reader.read_line(&mut line)? == 0 {
+                return Err("MPD closed the connection".into());
+            }
             let trimmed = line.trim();
 
             if trimmed == "OK" {
@@ -103,28 +506,17 @@ impl MpdClient {
         artist: Option<&str>,
     ) -> Result<Vec<(String, String)>, Box<dyn std::error::Error>> {
         let cmd = if let Some(artist) = artist {
-            format!("find albumartist \"{}\"", artist.replace('"', "\\\""))
+            format!("find albumartist {}", quote_argument(artist))
         } else {
             "listallinfo".to_string()
         };
 
         let lines = self.send_command(&cmd)?;
-        let mut albums = HashSet::new();
-        let mut current_artist = String::new();
-        let mut current_album = String::new();
-
-        for line in lines {
-            if let Some(value) = line.strip_prefix("AlbumArtist: ") {
-                current_artist = value.to_string();
-            } else if let Some(value) = line.strip_prefix("Album: ") {
-                current_album = value.to_string();
-            } else if line.starts_with("file: ")
-                && !current_artist.is_empty()
-                && !current_album.is_empty()
-            {
-                albums.insert((current_artist.clone(), current_album.clone()));
-            }
-        }
+        let albums: HashSet<(String, String)> = parse_tracks(&lines)
+            .into_iter()
+            .filter(|track| !track.artist.is_empty() && !track.album.is_empty())
+            .map(|track| (track.artist, track.album))
+            .collect();
 
         Ok(albums.into_iter().collect())
     }
@@ -144,92 +536,109 @@ impl MpdClient {
         Ok(artists)
     }
 
-    fn list_songs(
+    fn list_tracks(
         &mut self,
         artist: Option<&str>,
         album: Option<&str>,
-    ) -> Result<Vec<String>, Box<dyn std::error::Error>> {
-        let cmd = if let (Some(artist), Some(album)) = (artist, album) {
-            format!(
-                "find albumartist \"{}\" album \"{}\"",
-                artist.replace('"', "\\\""),
-                album.replace('"', "\\\"")
-            )
-        } else {
-            "listallinfo".to_string()
+    ) -> Result<Vec<Track>, Box<dyn std::error::Error>> {
+        let cmd = match (artist, album) {
+            (Some(artist), Some(album)) => format!(
+                "find albumartist {} album {}",
+                quote_argument(artist),
+                quote_argument(album)
+            ),
+            (Some(artist), None) => format!("find albumartist {}", quote_argument(artist)),
+            _ => "listallinfo".to_string(),
         };
 
         let lines = self.send_command(&cmd)?;
-        let mut songs = Vec::new();
-        let mut current_title = String::new();
-        let mut current_artist = String::new();
+        Ok(parse_tracks(&lines))
+    }
 
-        for line in lines {
-            if let Some(title) = line.strip_prefix("Title: ") {
-                current_title = title.to_string();
-            } else if let Some(artist) = line.strip_prefix("AlbumArtist: ") {
-                current_artist = artist.to_string();
-            } else if line.starts_with("file: ") && !current_title.is_empty() {
+    fn list_songs(
+        &mut self,
+        artist: Option<&str>,
+        album: Option<&str>,
+    ) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        let tracks = self.list_tracks(artist, album)?;
+        let songs = tracks
+            .into_iter()
+            .filter(|track| !track.title.is_empty())
+            .map(|track| {
                 if artist.is_none() && album.is_none() {
                     // Return format "artist\ttitle" for all songs
-                    songs.push(format!("{}\t{}", current_artist, current_title));
+                    format!("{}\t{}", track.artist, track.title)
                 } else {
                     // Return just title for specific album
-                    songs.push(current_title.clone());
+                    track.title
                 }
-                current_title.clear();
-                current_artist.clear();
-            }
-        }
-
-        // Handle the last song if it wasn't processed due to missing file: line
-        if !current_title.is_empty() {
-            if artist.is_none() && album.is_none() {
-                // Return format "artist\ttitle" for all songs
-                songs.push(format!("{}\t{}", current_artist, current_title));
-            } else {
-                // Return just title for specific album
-                songs.push(current_title);
-            }
-        }
+            })
+            .collect();
 
         Ok(songs)
     }
 
     fn get_playlist(&mut self) -> Result<Vec<Track>, Box<dyn std::error::Error>> {
         let lines = self.send_command("playlistinfo")?;
-        let mut tracks = Vec::new();
-        let mut current_track = Track {
-            artist: String::new(),
-            album: String::new(),
-            title: String::new(),
-            track: None,
-            file: String::new(),
-        };
+        Ok(parse_tracks(&lines))
+    }
+
+    fn current_song(&mut self) -> Result<Option<Track>, Box<dyn std::error::Error>> {
+        let lines = self.send_command("currentsong")?;
+        Ok(parse_tracks(&lines).into_iter().next())
+    }
+
+    fn list_playlists(&mut self) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        let lines = self.send_command("listplaylists")?;
+        let mut playlists = Vec::new();
 
         for line in lines {
-            if let Some(value) = line.strip_prefix("AlbumArtist: ") {
-                current_track.artist = value.to_string();
-            } else if let Some(value) = line.strip_prefix("Album: ") {
-                current_track.album = value.to_string();
-            } else if let Some(value) = line.strip_prefix("Title: ") {
-                current_track.title = value.to_string();
-            } else if let Some(value) = line.strip_prefix("Track: ") {
-                current_track.track = Some(value.to_string());
-            } else if let Some(value) = line.strip_prefix("file: ") {
-                current_track.file = value.to_string();
-                tracks.push(current_track.clone());
-                current_track = Track {
-                    artist: String::new(),
-                    album: String::new(),
-                    title: String::new(),
-                    track: None,
-                    file: String::new(),
-                };
+            if let Some(name) = line.strip_prefix("playlist: ") {
+                playlists.push(name.to_string());
             }
         }
 
-        Ok(tracks)
+        playlists.sort();
+        Ok(playlists)
+    }
+
+    fn list_playlist_files(
+        &mut self,
+        name: &str,
+    ) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        let lines = self.send_command(&format!("listplaylist {}", quote_argument(name)))?;
+        Ok(lines
+            .into_iter()
+            .filter_map(|line| line.strip_prefix("file: ").map(str::to_string))
+            .collect())
+    }
+
+    fn save_playlist(&mut self, name: &str) -> Result<(), Box<dyn std::error::Error>> {
+        self.send_command(&format!("save {}", quote_argument(name)))?;
+        Ok(())
+    }
+
+    fn add_to_playlist(
+        &mut self,
+        name: &str,
+        file: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.send_command(&format!(
+            "playlistadd {} {}",
+            quote_argument(name),
+            quote_argument(file)
+        ))?;
+        Ok(())
+    }
+
+    fn delete_playlist(&mut self, name: &str) -> Result<(), Box<dyn std::error::Error>> {
+        self.send_command(&format!("rm {}", quote_argument(name)))?;
+        Ok(())
+    }
+
+    fn search(&mut self, filter: &str) -> Result<Vec<Track>, Box<dyn std::error::Error>> {
+        let lines = self.send_command(&format!("search {}", quote_argument(filter)))?;
+        Ok(parse_tracks(&lines))
     }
 
     fn get_status(
@@ -249,528 +658,4266 @@ impl MpdClient {
         Ok(status)
     }
 
-    fn find_song_album(
+    fn list_directory(&mut self, path: &str) -> Result<Vec<DirEntry>, Box<dyn std::error::Error>> {
+        let cmd = format!("lsinfo {}", quote_argument(path));
+        let lines = self.send_command(&cmd)?;
+        let mut entries = Vec::new();
+
+        for line in lines {
+            if let Some(dir) = line.strip_prefix("directory: ") {
+                entries.push(DirEntry::Directory(dir.to_string()));
+            } else if let Some(file) = line.strip_prefix("file: ") {
+                entries.push(DirEntry::File(file.to_string()));
+            }
+        }
+
+        Ok(entries)
+    }
+
+    fn get_sticker(
         &mut self,
-        artist: &str,
-        title: &str,
+        uri: &str,
+        name: &str,
     ) -> Result<Option<String>, Box<dyn std::error::Error>> {
         let cmd = format!(
-            "find albumartist \"{}\" title \"{}\"",
-            artist.replace('"', "\\\""),
-            title.replace('"', "\\\"")
+            "sticker get song {} {}",
+            quote_argument(uri),
+            quote_argument(name)
         );
-        let lines = self.send_command(&cmd)?;
+        let lines = match self.send_command(&cmd) {
+            Ok(lines) => lines,
+            // "no such sticker"
+            Err(err) if err.to_string().contains("ACK [50@") => return Ok(None),
+            Err(err) => return Err(err),
+        };
 
         for line in lines {
-            if let Some(album) = line.strip_prefix("Album: ") {
-                return Ok(Some(album.to_string()));
+            if let Some(sticker) = line.strip_prefix("sticker: ") {
+                if let Some((_, value)) = sticker.split_once('=') {
+                    return Ok(Some(value.to_string()));
+                }
             }
         }
 
         Ok(None)
     }
-}
-
-struct MusicSelector {
-    mpd: MpdClient,
-}
 
-impl MusicSelector {
-    fn new() -> Result<Self, Box<dyn std::error::Error>> {
-        let mpd = MpdClient::connect()?;
-        Ok(MusicSelector { mpd })
+    fn set_sticker(
+        &mut self,
+        uri: &str,
+        name: &str,
+        value: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.send_command(&format!(
+            "sticker set song {} {} {}",
+            quote_argument(uri),
+            quote_argument(name),
+            quote_argument(value)
+        ))?;
+        Ok(())
     }
 
-    fn rofi_select(
-        &self,
-        items: &[String],
-        prompt: &str,
-        selected_row: usize,
-        use_column_formatting: bool,
-    ) -> Result<(Option<String>, bool), Box<dyn std::error::Error>> {
-        if items.is_empty() {
-            return Ok((None, false));
-        }
-
-        let input_text = items.join("\n");
-
-        let formatted_input = if use_column_formatting {
-            match Command::new("column")
-                .args(["-o", "           ", "-s", "\t", "-t"])
-                .stdin(Stdio::piped())
-                .stdout(Stdio::piped())
-                .spawn()
-            {
-                Ok(mut child) => {
-                    if let Some(stdin) = child.stdin.take() {
-                        let mut stdin = stdin;
-                        let _ = stdin.write_all(input_text.as_bytes());
-                    }
+    /// Returns the value of a sticker for every song that has it.
+    fn find_stickers(
+        &mut self,
+        name: &str,
+    ) -> Result<HashMap<String, String>, Box<dyn std::error::Error>> {
+        let lines =
+            self.send_command(&format!("sticker find song \"\" {}", quote_argument(name)))?;
+        let mut stickers = HashMap::new();
+        let mut current_file = None;
 
-                    match child.wait_with_output() {
-                        Ok(output) if output.status.success() => {
-                            String::from_utf8_lossy(&output.stdout).to_string()
-                        }
-                        _ => input_text,
-                    }
+        for line in lines {
+            if let Some(file) = line.strip_prefix("file: ") {
+                current_file = Some(file.to_string());
+            } else if let Some(sticker) = line.strip_prefix("sticker: ") {
+                if let (Some(file), Some((_, value))) =
+                    (current_file.take(), sticker.split_once('='))
+                {
+                    stickers.insert(file, value.to_string());
                 }
-                Err(_) => input_text,
             }
-        } else {
-            input_text
-        };
-
-        let mut cmd = Command::new("rofi")
-            .args(["-i", "-dmenu", "-no-custom", "-format", "d"])
-            .args(["-kb-custom-1", "Ctrl+Return", "-p", prompt])
-            .args(["-selected-row", &selected_row.to_string()])
-            .stdin(Stdio::piped())
-            .stdout(Stdio::piped())
-            .spawn()?;
-
-        if let Some(stdin) = cmd.stdin.take() {
-            let mut stdin = stdin;
-            let _ = stdin.write_all(formatted_input.as_bytes());
         }
 
-        let output = cmd.wait_with_output()?;
-        let exit_code = output.status.code().unwrap_or(1);
+        Ok(stickers)
+    }
 
-        if exit_code == 1 {
-            return Ok((None, false));
-        }
+    fn first_album_file(
+        &mut self,
+        artist: &str,
+        album: &str,
+    ) -> Result<Option<String>, Box<dyn std::error::Error>> {
+        let cmd = format!(
+            "find albumartist {} album {} window 0:1",
+            quote_argument(artist),
+            quote_argument(album)
+        );
+        let lines = self.send_command(&cmd)?;
 
-        if let Ok(stdout) = String::from_utf8(output.stdout) {
-            let stdout = stdout.trim();
-            if !stdout.is_empty() {
-                if let Ok(index) = stdout.parse::<usize>() {
-                    if index > 0 && index <= items.len() {
-                        let selected = items[index - 1].clone();
-                        let is_queue = exit_code == 10;
-                        return Ok((Some(selected), is_queue));
-                    }
-                }
+        for line in lines {
+            if let Some(file) = line.strip_prefix("file: ") {
+                return Ok(Some(file.to_string()));
             }
         }
 
-        Ok((None, false))
-    }
-
-    fn get_artists(&mut self) -> Result<Vec<String>, Box<dyn std::error::Error>> {
-        self.mpd.list_artists()
+        Ok(None)
     }
 
-    fn get_albums(
+    /// Sends a command with a binary response (`albumart`, `readpicture`) and
+    /// returns the total size and the chunk that was read. Returns `None` if
+    /// MPD reports that no picture exists.
+    fn send_binary_command(
         &mut self,
-        artist: Option<&str>,
-    ) -> Result<Vec<(String, String)>, Box<dyn std::error::Error>> {
-        self.mpd.list_albums(artist)
-    }
+        cmd: &str,
+    ) -> Result<Option<BinaryChunk>, Box<dyn std::error::Error>> {
+        writeln!(self.stream, "{}", cmd)?;
 
-    fn get_songs(
-        &mut self,
-        artist: Option<&str>,
-        album: Option<&str>,
-    ) -> Result<Vec<String>, Box<dyn std::error::Error>> {
-        self.mpd.list_songs(artist, album)
-    }
+        let mut reader = BufReader::new(&self.stream);
+        let mut size = 0;
+        let mut data = Vec::new();
+        let mut line = String::new();
 
-    fn play_song(
-        &mut self,
-        artist: &str,
-        album: Option<&str>,
+        loop {
+            line.clear();
+            if reader.read_line(&mut line)? == 0 {
+                return Err("MPD closed the connection".into());
+            }
+            let trimmed = line.trim();
+
+            if trimmed == "OK" {
+                break;
+            }
+            if trimmed.starts_with("ACK [50@") {
+                // "No file exists"
+                return Ok(None);
+            }
+            if trimmed.starts_with("ACK") {
+                return Err(format!("MPD error: {}", trimmed).into());
+            }
+
+            if let Some(value) = trimmed.strip_prefix("size: ") {
+                size = value.parse()?;
+            } else if let Some(value) = trimmed.strip_prefix("binary: ") {
+                data = vec![0; value.parse()?];
+                reader.read_exact(&mut data)?;
+                // The binary data is followed by a newline
+                let mut newline = [0; 1];
+                reader.read_exact(&mut newline)?;
+            }
+        }
+
+        if data.is_empty() {
+            return Ok(None);
+        }
+
+        Ok(Some((size, data)))
+    }
+
+    /// Reads a complete picture with `albumart` or `readpicture`, which MPD
+    /// sends in chunks.
+    fn read_picture(
+        &mut self,
+        command: &str,
+        uri: &str,
+    ) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error>> {
+        let mut picture = Vec::new();
+
+        loop {
+            let cmd = format!("{} {} {}", command, quote_argument(uri), picture.len());
+            let Some((size, chunk)) = self.send_binary_command(&cmd)? else {
+                return Ok(None);
+            };
+            picture.extend_from_slice(&chunk);
+
+            if picture.len() >= size {
+                return Ok(Some(picture));
+            }
+        }
+    }
+
+    /// Returns the cover image for a song: the cover file from the song's
+    /// directory, or else the picture embedded in the song.
+    fn album_art(&mut self, uri: &str) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error>> {
+        if let Some(picture) = self.read_picture("albumart", uri)? {
+            return Ok(Some(picture));
+        }
+        self.read_picture("readpicture", uri)
+    }
+
+    fn find_song_album(
+        &mut self,
+        artist: &str,
         title: &str,
-        queue_mode: bool,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        // If no album provided, find the album that contains this song
-        let actual_album = if let Some(album) = album {
-            Some(album.to_string())
-        } else {
-            self.mpd.find_song_album(artist, title)?
-        };
-        if !queue_mode {
-            Command::new("mpc").arg("clear").output()?;
+    ) -> Result<Option<String>, Box<dyn std::error::Error>> {
+        let cmd = format!(
+            "find albumartist {} title {}",
+            quote_argument(artist),
+            quote_argument(title)
+        );
+        let lines = self.send_command(&cmd)?;
 
-            // Build findadd command - add album filter if we found/have an album
-            let mut args = vec!["findadd"];
-            if let Some(ref album) = actual_album {
-                args.extend_from_slice(&["album", album]);
+        for line in lines {
+            if let Some(album) = line.strip_prefix("Album: ") {
+                return Ok(Some(album.to_string()));
             }
-            args.extend_from_slice(&["albumartist", artist]);
+        }
 
-            Command::new("mpc").args(&args).output()?;
+        Ok(None)
+    }
+}
 
-            let playlist = Command::new("mpc")
-                .args(["playlist", "-f", "%title%"])
-                .output()?;
+/// A named album list such as the quarantine list. Keeps every line of the
+/// file so that comments and the order of entries survive rewrites.
+///
+/// Each entry is a line of comma separated, double quoted fields with `\"` and
+/// `\\` escapes (other backslashes are kept as is): artist and album, followed
+/// by optional `key="value"` fields (`added`, `note`, `source`, `mbid`). `#`
+/// starts a comment.
+struct AlbumList {
+    path: PathBuf,
+    lines: Vec<ListLine>,
+    /// Parse errors as `path:line: message`
+    errors: Vec<String>,
+}
 
-            let playlist_str = String::from_utf8_lossy(&playlist.stdout);
-            let songs: Vec<&str> = playlist_str.trim().split('\n').collect();
+enum ListLine {
+    Entry {
+        entry: ListEntry,
+        text: String,
+    },
+    /// Comments, blank lines and lines that failed to parse
+    Other(String),
+}
 
-            if let Some(position) = songs.iter().position(|&s| s == title) {
-                Command::new("mpc")
-                    .args(["play", &(position + 1).to_string()])
-                    .output()?;
-                println!(
-                    "Playing:\n{}\n{}\n{}",
-                    artist,
-                    actual_album.as_deref().unwrap_or(""),
-                    title
-                );
-            } else {
-                Command::new("mpc").arg("play").output()?;
-                println!("Could not find song '{}' in playlist", title);
+#[derive(Debug, Clone, Default, PartialEq)]
+struct ListEntry {
+    artist: String,
+    album: String,
+    /// Date the album was added, as `YYYY-MM-DD`
+    added: Option<String>,
+    note: Option<String>,
+    source: Option<String>,
+    /// MusicBrainz release id
+    mbid: Option<String>,
+}
+
+impl ListEntry {
+    fn new(artist: &str, album: &str) -> Self {
+        ListEntry {
+            artist: artist.to_string(),
+            album: album.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Parses a line of a list file. Returns `None` for blank lines and comments.
+    fn parse(line: &str) -> Result<Option<Self>, String> {
+        let mut chars = line.chars().peekable();
+        let mut positional = Vec::new();
+        let mut entry = ListEntry::default();
+
+        loop {
+            while chars.next_if(|c| c.is_whitespace()).is_some() {}
+            match chars.peek() {
+                None | Some('#') => break,
+                _ => {}
             }
-        } else {
-            // Queue the specific song
-            let mut args = vec!["findadd", "albumartist", artist];
-            if let Some(ref album) = actual_album {
-                args.extend_from_slice(&["album", album]);
+
+            let mut key = String::new();
+            while let Some(c) = chars.next_if(|c| c.is_ascii_alphanumeric() || *c == '_') {
+                key.push(c);
+            }
+            if !key.is_empty() && chars.next_if_eq(&'=').is_none() {
+                return Err(format!("expected '=' after '{}'", key));
             }
-            args.extend_from_slice(&["title", title]);
 
-            Command::new("mpc").args(&args).output()?;
-            println!(
-                "Queued:\n{}\n{}\n{}",
-                artist,
-                actual_album.as_deref().unwrap_or(""),
-                title
-            );
+            if chars.next_if_eq(&'"').is_none() {
+                return Err("expected a double quoted field".to_string());
+            }
+            let mut value = String::new();
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some('\\') => match chars.next() {
+                        Some(c @ ('"' | '\\')) => value.push(c),
+                        // Older lists weren't escaped, keep e.g. `AC\DC` as is
+                        Some(c) => {
+                            value.push('\\');
+                            value.push(c);
+                        }
+                        None => return Err("unterminated field".to_string()),
+                    },
+                    Some(c) => value.push(c),
+                    None => return Err("unterminated field".to_string()),
+                }
+            }
+
+            match key.as_str() {
+                "" if positional.len() < 2 => positional.push(value),
+                "" => return Err("unexpected field after artist and album".to_string()),
+                "added" => entry.added = Some(value),
+                "note" => entry.note = Some(value),
+                "source" => entry.source = Some(value),
+                "mbid" => entry.mbid = Some(value),
+                _ => return Err(format!("unknown field '{}'", key)),
+            }
+
+            while chars.next_if(|c| c.is_whitespace()).is_some() {}
+            match chars.peek() {
+                None | Some('#') => break,
+                Some(',') => {
+                    chars.next();
+                }
+                Some(c) => return Err(format!("expected ',' but found '{}'", c)),
+            }
         }
 
-        Ok(())
+        match positional.len() {
+            0 if entry.added.is_none()
+                && entry.note.is_none()
+                && entry.source.is_none()
+                && entry.mbid.is_none() =>
+            {
+                Ok(None)
+            }
+            2 => {
+                entry.album = positional.pop().unwrap();
+                entry.artist = positional.pop().unwrap();
+                Ok(Some(entry))
+            }
+            _ => Err("expected artist and album".to_string()),
+        }
     }
 
-    fn show_notification(&self, artist: &str, album: &str, title: Option<&str>) {
-        let (summary, message) = if let Some(title) = title {
-            ("Now Playing", format!("{}\n{}\n{}", artist, album, title))
-        } else {
-            ("Now Playing Album", format!("{}\n{}", artist, album))
+    fn format(&self) -> String {
+        let mut text = format!(
+            "{}, {}",
+            quote_argument(&self.artist),
+            quote_argument(&self.album)
+        );
+        let fields = [
+            ("added", &self.added),
+            ("note", &self.note),
+            ("source", &self.source),
+            ("mbid", &self.mbid),
+        ];
+        for (key, value) in fields {
+            if let Some(value) = value {
+                text.push_str(&format!(", {}={}", key, quote_argument(value)));
+            }
+        }
+        text
+    }
+}
+
+impl AlbumList {
+    /// Reads the file; a missing file is an empty list.
+    fn load(path: PathBuf) -> Result<Self, Box<dyn std::error::Error>> {
+        let content = match fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(err) => return Err(err.into()),
         };
 
-        let _ = Command::new("notify-send")
-            .args(["-t", "3000", summary, &message])
-            .output();
+        let mut lines = Vec::new();
+        let mut errors = Vec::new();
+        for (number, line) in content.lines().enumerate() {
+            match ListEntry::parse(line) {
+                Ok(Some(entry)) => lines.push(ListLine::Entry {
+                    entry,
+                    text: line.to_string(),
+                }),
+                Ok(None) => lines.push(ListLine::Other(line.to_string())),
+                Err(err) => {
+                    errors.push(format!("{}:{}: {}", path.display(), number + 1, err));
+                    lines.push(ListLine::Other(line.to_string()));
+                }
+            }
+        }
+
+        Ok(AlbumList {
+            path,
+            lines,
+            errors,
+        })
     }
 
-    fn play_random_album(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        let albums = self.get_albums(None)?;
-        if albums.is_empty() {
-            println!("No albums found");
-            return Ok(());
+    fn entries(&self) -> Vec<&ListEntry> {
+        self.lines
+            .iter()
+            .filter_map(|line| match line {
+                ListLine::Entry { entry, .. } => Some(entry),
+                ListLine::Other(_) => None,
+            })
+            .collect()
+    }
+
+    fn albums(&self) -> Vec<(String, String)> {
+        self.entries()
+            .into_iter()
+            .map(|entry| (entry.artist.clone(), entry.album.clone()))
+            .collect()
+    }
+
+    fn contains(&self, artist: &str, album: &str) -> bool {
+        self.entries()
+            .iter()
+            .any(|entry| entry.artist == artist && entry.album == album)
+    }
+
+    /// Appends an entry. Returns false if the album is already listed.
+    fn add(&mut self, entry: ListEntry) -> bool {
+        if self.contains(&entry.artist, &entry.album) {
+            return false;
         }
+        let text = entry.format();
+        self.lines.push(ListLine::Entry { entry, text });
+        true
+    }
 
-        let (artist, album) = albums.choose(&mut rand::thread_rng()).unwrap();
+    /// Removes an entry. Returns false if the album isn't listed.
+    fn remove(&mut self, artist: &str, album: &str) -> bool {
+        let before = self.lines.len();
+        self.lines.retain(|line| {
+            !matches!(line, ListLine::Entry { entry, .. } if entry.artist == artist && entry.album == album)
+        });
+        self.lines.len() != before
+    }
 
-        Command::new("mpc").arg("clear").output()?;
-        Command::new("mpc")
-            .args(["findadd", "album", album, "albumartist", artist])
-            .output()?;
-        Command::new("mpc").arg("play").output()?;
+    /// Changes the artist and album of an entry, keeping its other fields.
+    fn rename(&mut self, artist: &str, album: &str, new_artist: &str, new_album: &str) {
+        for line in &mut self.lines {
+            if let ListLine::Entry { entry, text } = line {
+                if entry.artist == artist && entry.album == album {
+                    entry.artist = new_artist.to_string();
+                    entry.album = new_album.to_string();
+                    *text = entry.format();
+                }
+            }
+        }
+    }
+
+    /// Writes the file atomically: a temporary file is renamed over the old one.
+    fn save(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let mut content = String::new();
+        for line in &self.lines {
+            match line {
+                ListLine::Entry { text, .. } | ListLine::Other(text) => {
+                    content.push_str(text);
+                    content.push('\n');
+                }
+            }
+        }
+
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut temp_path = self.path.clone().into_os_string();
+        temp_path.push(format!(".{}.tmp", std::process::id()));
+        let temp_path = PathBuf::from(temp_path);
 
-        println!("Playing random album:\n{}\n{}", artist, album);
-        self.show_notification(artist, album, None);
+        let mut file = fs::File::create(&temp_path)?;
+        file.write_all(content.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&temp_path, &self.path)?;
 
         Ok(())
     }
+}
 
-    fn load_quarantine_albums(&self) -> Result<Vec<(String, String)>, Box<dyn std::error::Error>> {
-        let quarantine_path = format!("{}/music/quarantine", std::env::var("HOME")?);
+/// Async MPD connection that waits for events with `idle`.
+struct IdleConnection {
+    stream: tokio::io::BufReader<tokio::net::TcpStream>,
+}
 
-        if !Path::new(&quarantine_path).exists() {
-            println!("Quarantine file not found: {}", quarantine_path);
-            return Ok(Vec::new());
+impl IdleConnection {
+    async fn connect() -> Result<Self, Box<dyn std::error::Error>> {
+        let stream = tokio::net::TcpStream::connect(MPD_ADDRESS).await?;
+        let mut stream = tokio::io::BufReader::new(stream);
+
+        let mut greeting = String::new();
+        stream.read_line(&mut greeting).await?;
+        if !greeting.starts_with("OK MPD") {
+            return Err("Invalid MPD greeting".into());
         }
 
-        let content = fs::read_to_string(&quarantine_path)?;
-        let re = Regex::new(r#"^"([^"]*)",\s*"([^"]*)"$"#)?;
-        let mut albums = Vec::new();
+        Ok(IdleConnection { stream })
+    }
 
-        for line in content.lines() {
-            let line = line.trim();
-            if line.is_empty() {
-                continue;
+    /// Waits until one of the subsystems changes and returns the changed ones.
+    async fn idle(
+        &mut self,
+        subsystems: &[&str],
+    ) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        let cmd = format!("idle {}\n", subsystems.join(" "));
+        self.stream.get_mut().write_all(cmd.as_bytes()).await?;
+
+        let mut changed = Vec::new();
+        let mut line = String::new();
+
+        loop {
+            line.clear();
+            if self.stream.read_line(&mut line).await? == 0 {
+                return Err("MPD closed the connection".into());
             }
+            let trimmed = line.trim();
 
-            if let Some(captures) = re.captures(line) {
-                let artist = captures.get(1).unwrap().as_str().to_string();
-                let album = captures.get(2).unwrap().as_str().to_string();
-                albums.push((artist, album));
+            if trimmed == "OK" {
+                break;
+            }
+            if trimmed.starts_with("ACK") {
+                return Err(format!("MPD error: {}", trimmed).into());
+            }
+            if let Some(subsystem) = trimmed.strip_prefix("changed: ") {
+                changed.push(subsystem.to_string());
             }
         }
 
-        Ok(albums)
+        Ok(changed)
     }
+}
 
-    fn select_quarantine_album(
-        &self,
-        random_mode: bool,
-    ) -> Result<Option<(String, String, bool)>, Box<dyn std::error::Error>> {
-        let albums = self.load_quarantine_albums()?;
-        if albums.is_empty() {
-            println!("No quarantine albums found");
-            return Ok(None);
+/// A long-running mode driven by MPD `idle` events.
+enum IdleTask {
+    Daemon(DaemonState),
+    AutoDj(AutoDjConfig),
+}
+
+impl IdleTask {
+    fn subsystems(&self) -> &'static [&'static str] {
+        match self {
+            IdleTask::Daemon(_) => &["player", "playlist", "database", "options"],
+            IdleTask::AutoDj(_) => &["playlist", "player"],
         }
+    }
+}
 
-        if random_mode {
-            let (artist, album) = albums.choose(&mut rand::thread_rng()).unwrap();
-            Ok(Some((artist.clone(), album.clone(), false)))
-        } else {
-            let tab_separated_items: Vec<String> = albums
-                .iter()
-                .map(|(artist, album)| format!("{}\t{}", artist, album))
-                .collect();
+/// Where the auto-DJ takes new music from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum AutoDjSource {
+    Library,
+    List(String),
+    Genre(String),
+    Playlist(String),
+}
+
+fn parse_autodj_source(source: &str) -> Result<AutoDjSource, String> {
+    match source.split_once(':') {
+        None if source == "library" => Ok(AutoDjSource::Library),
+        None if source == "quarantine" => Ok(AutoDjSource::List(QUARANTINE_LIST.to_string())),
+        Some(("list", name)) if !name.is_empty() => Ok(AutoDjSource::List(name.to_string())),
+        Some(("genre", genre)) if !genre.is_empty() => Ok(AutoDjSource::Genre(genre.to_string())),
+        Some(("playlist", name)) if !name.is_empty() => {
+            Ok(AutoDjSource::Playlist(name.to_string()))
+        }
+        _ => Err(format!(
+            "invalid source '{}', expected library, quarantine, list:NAME, genre:NAME or playlist:NAME",
+            source
+        )),
+    }
+}
+
+struct AutoDjConfig {
+    /// Top up when fewer songs than this remain after the current one
+    threshold: usize,
+    source: AutoDjSource,
+    /// How to pick from the library; always appends
+    options: RandomOptions,
+    /// Created once from `--seed`, so every top-up picks something new
+    rng: Box<StdRng>,
+}
+
+/// What the daemon remembers between events.
+#[derive(Debug, Default)]
+struct DaemonState {
+    /// The song that was current at the last `player` event
+    current: Option<CurrentSong>,
+    /// Complete listens after which a quarantine album may graduate; 0 disables
+    graduate_after: usize,
+    /// Add new library albums to quarantine when the database changes
+    import_new: bool,
+}
+
+#[derive(Debug)]
+struct CurrentSong {
+    song: Track,
+    /// Unix time at which the song became current
+    started: u64,
+    duration: f64,
+    /// Elapsed time reported by MPD at `updated`
+    elapsed: f64,
+    updated: std::time::Instant,
+    playing: bool,
+}
+
+impl CurrentSong {
+    /// Estimates how much of the song has been listened to, from 0 to 1.
+    fn listened(&self) -> f64 {
+        let mut elapsed = self.elapsed;
+        if self.playing {
+            elapsed += self.updated.elapsed().as_secs_f64();
+        }
+        if self.duration <= 0.0 {
+            return 0.0;
+        }
+        (elapsed / self.duration).clamp(0.0, 1.0)
+    }
+}
+
+/// One played track in the history log.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct HistoryEntry {
+    /// Unix time at which the track started
+    timestamp: u64,
+    file: String,
+    artist: String,
+    album: String,
+    title: String,
+    /// Fraction of the track that was listened to, from 0 to 1
+    listened: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+enum OutputMode {
+    /// Human readable messages
+    #[default]
+    Text,
+    /// One JSON object per line for every event and error
+    Json,
+    /// No output on stdout, e.g. when stdout is read by rofi in script mode
+    #[value(skip)]
+    Quiet,
+}
+
+/// Something a command did, printed as a JSON object with `--output json`.
+#[derive(Debug, Default, Serialize)]
+struct Event {
+    event: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    artist: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    album: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    title: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    file: Option<String>,
+    /// Stored MPD playlist
+    #[serde(skip_serializing_if = "Option::is_none")]
+    playlist: Option<String>,
+    /// Album list such as the quarantine list
+    #[serde(skip_serializing_if = "Option::is_none")]
+    list: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rating: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    added: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    note: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message: Option<String>,
+}
+
+impl Event {
+    fn new(event: &'static str) -> Self {
+        Event {
+            event,
+            ..Default::default()
+        }
+    }
+
+    fn album(event: &'static str, artist: &str, album: &str) -> Self {
+        Event {
+            artist: Some(artist.to_string()),
+            album: Some(album.to_string()),
+            ..Event::new(event)
+        }
+    }
+
+    fn song(event: &'static str, artist: &str, album: &str, title: &str) -> Self {
+        Event {
+            title: Some(title.to_string()),
+            ..Event::album(event, artist, album)
+        }
+    }
+
+    fn file(event: &'static str, file: &str) -> Self {
+        Event {
+            file: Some(file.to_string()),
+            ..Event::new(event)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+enum NotificationBackend {
+    /// D-Bus, falling back to notify-send
+    #[default]
+    Auto,
+    /// org.freedesktop.Notifications over the session bus
+    Dbus,
+    /// The notify-send command
+    NotifySend,
+    /// No notifications
+    None,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+enum NotificationUrgency {
+    Low,
+    #[default]
+    Normal,
+    Critical,
+}
+
+impl NotificationUrgency {
+    fn name(self) -> &'static str {
+        match self {
+            NotificationUrgency::Low => "low",
+            NotificationUrgency::Normal => "normal",
+            NotificationUrgency::Critical => "critical",
+        }
+    }
+}
+
+struct Notification {
+    summary: String,
+    message: String,
+    image: Option<PathBuf>,
+    urgency: NotificationUrgency,
+    /// Milliseconds until the notification expires; 0 keeps it until dismissed
+    timeout_ms: i32,
+    /// Buttons as (action, label) pairs; if any, wait for a click
+    actions: &'static [(&'static str, &'static str)],
+}
+
+/// Receives the action invoked on a notification, or `None` once it is closed.
+type ActionReceiver = std::sync::mpsc::Receiver<Option<String>>;
+
+#[derive(Debug, Clone, Default)]
+struct NotificationConfig {
+    backend: NotificationBackend,
+    urgency: NotificationUrgency,
+    actions: bool,
+    /// Summary template with `%artist%`, `%album%` and `%title%` placeholders
+    summary_template: Option<String>,
+    /// Body template with `%artist%`, `%album%` and `%title%` placeholders
+    body_template: Option<String>,
+}
+
+struct MusicSelector {
+    mpd: MpdClient,
+    output: OutputMode,
+    show_icons: bool,
+    album_sort: AlbumSort,
+    list_sort: ListSort,
+    /// Only offer albums and songs rated at least this many stars
+    min_rating: Option<u8>,
+    notification: NotificationConfig,
+    /// Threads still waiting on notification answers
+    pending: Vec<std::thread::JoinHandle<()>>,
+    /// Counts the now playing notifications shown, shared with detached
+    /// selectors; a waiter only acts while its notification is the latest
+    notification_generation: Arc<AtomicU64>,
+}
+
+impl MusicSelector {
+    fn new(output: OutputMode) -> Result<Self, Box<dyn std::error::Error>> {
+        let mpd = MpdClient::connect()?;
+        Ok(MusicSelector {
+            mpd,
+            output,
+            show_icons: false,
+            album_sort: AlbumSort::default(),
+            list_sort: ListSort::default(),
+            min_rating: None,
+            notification: NotificationConfig::default(),
+            pending: Vec::new(),
+            notification_generation: Arc::default(),
+        })
+    }
+
+    /// Returns a selector with the same settings and its own MPD connection,
+    /// for work done on another thread.
+    fn detached(&self) -> Result<MusicSelector, Box<dyn std::error::Error>> {
+        let mut selector = MusicSelector::new(self.output)?;
+        selector.show_icons = self.show_icons;
+        selector.album_sort = self.album_sort;
+        selector.list_sort = self.list_sort;
+        selector.min_rating = self.min_rating;
+        selector.notification = self.notification.clone();
+        selector.notification_generation = self.notification_generation.clone();
+        Ok(selector)
+    }
+
+    /// Runs `task` on a detached selector in the background; `wait_pending`
+    /// joins it.
+    fn spawn_detached<F>(&mut self, task: F) -> Result<(), Box<dyn std::error::Error>>
+    where
+        F: FnOnce(&mut MusicSelector) + Send + 'static,
+    {
+        let mut selector = self.detached()?;
+        self.pending.retain(|handle| !handle.is_finished());
+        self.pending
+            .push(std::thread::spawn(move || task(&mut selector)));
+        Ok(())
+    }
+
+    /// Blocks until all background work has finished.
+    fn wait_pending(&mut self) {
+        for handle in self.pending.drain(..) {
+            let _ = handle.join();
+        }
+    }
+
+    fn report(&self, message: &str) {
+        self.report_events(
+            message,
+            vec![Event {
+                message: Some(message.to_string()),
+                ..Event::new("message")
+            }],
+        );
+    }
+
+    /// Prints a failure that doesn't end the command to stderr, or as an
+    /// `error` event with `--output json`.
+    fn report_error(&self, message: &str) {
+        match self.output {
+            OutputMode::Json => self.emit(&Event {
+                message: Some(message.to_string()),
+                ..Event::new("error")
+            }),
+            _ => eprintln!("{}", message),
+        }
+    }
+
+    /// Prints `message`, or `event` as JSON with `--output json`.
+    fn report_event(&self, message: &str, event: Event) {
+        self.report_events(message, vec![event]);
+    }
+
+    fn report_events(&self, message: &str, events: Vec<Event>) {
+        match self.output {
+            OutputMode::Text => println!("{}", message),
+            OutputMode::Json => events.iter().for_each(|event| self.emit(event)),
+            OutputMode::Quiet => {}
+        }
+    }
+
+    /// Prints an event that has no text form, such as a menu selection, as JSON.
+    fn emit(&self, event: &Event) {
+        if self.output == OutputMode::Json {
+            match serde_json::to_string(event) {
+                Ok(json) => println!("{}", json),
+                Err(err) => eprintln!("Could not serialize event: {}", err),
+            }
+        }
+    }
+
+    fn rofi_select(
+        &self,
+        items: &[String],
+        prompt: &str,
+        selected_row: usize,
+        use_column_formatting: bool,
+    ) -> Result<(Option<String>, bool), Box<dyn std::error::Error>> {
+        self.rofi_select_with_icons(items, &[], prompt, selected_row, use_column_formatting)
+    }
+
+    /// Like `rofi_select`, but shows `icons[i]` next to `items[i]`.
+    fn rofi_select_with_icons(
+        &self,
+        items: &[String],
+        icons: &[Option<PathBuf>],
+        prompt: &str,
+        selected_row: usize,
+        use_column_formatting: bool,
+    ) -> Result<(Option<String>, bool), Box<dyn std::error::Error>> {
+        match self.rofi_select_index(
+            items,
+            icons,
+            prompt,
+            selected_row,
+            use_column_formatting,
+            &[],
+        )? {
+            Some((index, exit_code)) => Ok((Some(items[index].clone()), exit_code == 10)),
+            None => Ok((None, false)),
+        }
+    }
+
+    /// Runs rofi and returns the index of the selected item together with the
+    /// rofi exit code (0 for Enter, 10 for Ctrl+Enter, 11.. for further custom keys).
+    fn rofi_select_index(
+        &self,
+        items: &[String],
+        icons: &[Option<PathBuf>],
+        prompt: &str,
+        selected_row: usize,
+        use_column_formatting: bool,
+        extra_args: &[&str],
+    ) -> Result<Option<(usize, i32)>, Box<dyn std::error::Error>> {
+        if items.is_empty() {
+            return Ok(None);
+        }
+
+        let input_text = items.join("\n");
+
+        let formatted_input = if use_column_formatting {
+            match Command::new("column")
+                .args(["-o", "           ", "-s", "\t", "-t"])
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .spawn()
+            {
+                Ok(mut child) => {
+                    if let Some(stdin) = child.stdin.take() {
+                        let mut stdin = stdin;
+                        let _ = stdin.write_all(input_text.as_bytes());
+                    }
+
+                    match child.wait_with_output() {
+                        Ok(output) if output.status.success() => {
+                            String::from_utf8_lossy(&output.stdout).to_string()
+                        }
+                        _ => input_text,
+                    }
+                }
+                Err(_) => input_text,
+            }
+        } else {
+            input_text
+        };
+
+        let (formatted_input, icon_args): (String, &[&str]) = if icons.is_empty() {
+            (formatted_input, &[])
+        } else {
+            let rows: Vec<String> = formatted_input
+                .lines()
+                .zip(icons.iter().chain(std::iter::repeat(&None)))
+                .map(|(row, icon)| match icon {
+                    Some(icon) => format!("{}\0icon\x1f{}", row, icon.display()),
+                    None => row.to_string(),
+                })
+                .collect();
+            (rows.join("\n"), &["-show-icons"])
+        };
+
+        let mut cmd = Command::new("rofi")
+            .args(["-i", "-dmenu", "-no-custom", "-format", "d"])
+            .args(["-kb-custom-1", "Ctrl+Return", "-p", prompt])
+            .args(["-selected-row", &selected_row.to_string()])
+            .args(icon_args)
+            .args(extra_args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+
+        if let Some(stdin) = cmd.stdin.take() {
+            let mut stdin = stdin;
+            let _ = stdin.write_all(formatted_input.as_bytes());
+        }
+
+        let output = cmd.wait_with_output()?;
+        let exit_code = output.status.code().unwrap_or(1);
+
+        if exit_code == 1 {
+            return Ok(None);
+        }
+
+        if let Ok(stdout) = String::from_utf8(output.stdout) {
+            let stdout = stdout.trim();
+            if !stdout.is_empty() {
+                if let Ok(index) = stdout.parse::<usize>() {
+                    if index > 0 && index <= items.len() {
+                        return Ok(Some((index - 1, exit_code)));
+                    }
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Asks for free-text input. Returns `None` if rofi was cancelled or the input is empty.
+    fn rofi_input(&self, prompt: &str) -> Result<Option<String>, Box<dyn std::error::Error>> {
+        let output = Command::new("rofi")
+            .args(["-dmenu", "-p", prompt])
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .output()?;
+
+        if output.status.code().unwrap_or(1) != 0 {
+            return Ok(None);
+        }
+
+        let input = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        if input.is_empty() {
+            return Ok(None);
+        }
+
+        Ok(Some(input))
+    }
+
+    fn get_artists(&mut self) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        self.mpd.list_artists()
+    }
+
+    fn get_albums(
+        &mut self,
+        artist: Option<&str>,
+    ) -> Result<Vec<(String, String)>, Box<dyn std::error::Error>> {
+        self.mpd.list_albums(artist)
+    }
+
+    fn get_songs(
+        &mut self,
+        artist: Option<&str>,
+        album: Option<&str>,
+    ) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        self.mpd.list_songs(artist, album)
+    }
+
+    /// Returns the path of the cached cover of an album, fetching it from MPD
+    /// if it isn't cached yet. `uri` is any song of the album, if known.
+    fn cover_path(
+        &mut self,
+        artist: &str,
+        album: &str,
+        uri: Option<&str>,
+    ) -> Result<Option<PathBuf>, Box<dyn std::error::Error>> {
+        if let Some(cached) = cached_cover_path(artist, album)? {
+            return Ok(cached);
+        }
+        let cover_dir = cache_dir()?.join("covers");
+        let key = cover_key(artist, album);
+        let missing_marker = cover_dir.join(format!("{}.none", key));
+
+        let uri = match uri {
+            Some(uri) => uri.to_string(),
+            None => match self.mpd.first_album_file(artist, album)? {
+                Some(uri) => uri,
+                None => return Ok(None),
+            },
+        };
+
+        fs::create_dir_all(&cover_dir)?;
+        let Some(picture) = self.mpd.album_art(&uri)? else {
+            fs::write(&missing_marker, "")?;
+            return Ok(None);
+        };
+
+        let extension = if picture.starts_with(b"\xff\xd8") {
+            "jpg"
+        } else if picture.starts_with(b"\x89PNG") {
+            "png"
+        } else if picture.starts_with(b"GIF8") {
+            "gif"
+        } else if picture.get(8..12) == Some(b"WEBP") {
+            "webp"
+        } else {
+            "img"
+        };
+        let path = cover_dir.join(format!("{}.{}", key, extension));
+        fs::write(&path, picture)?;
+
+        Ok(Some(path))
+    }
+
+    /// Returns the menu icons for the given albums, or no icons if they are disabled.
+    fn album_icons(&mut self, albums: &[(String, String)]) -> Vec<Option<PathBuf>> {
+        if !self.show_icons {
+            return Vec::new();
+        }
+
+        let mut fetches = 0;
+        albums
+            .iter()
+            .map(|(artist, album)| self.menu_icon(artist, album, None, &mut fetches))
+            .collect()
+    }
+
+    /// Returns the cached cover of an album as a menu icon. Covers not cached
+    /// yet are fetched until `fetches` reaches `MAX_COVER_FETCHES`, so a cold
+    /// cache doesn't hold up the menu; later runs fill in the rest.
+    fn menu_icon(
+        &mut self,
+        artist: &str,
+        album: &str,
+        uri: Option<&str>,
+        fetches: &mut usize,
+    ) -> Option<PathBuf> {
+        match cached_cover_path(artist, album) {
+            Ok(Some(cached)) => cached,
+            Ok(None) if *fetches < MAX_COVER_FETCHES => {
+                *fetches += 1;
+                self.cover_path(artist, album, uri).ok().flatten()
+            }
+            _ => None,
+        }
+    }
+
+    /// Increments the `playcount` sticker of a song and sets `lastplayed` to now.
+    fn count_play(&mut self, file: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let plays = self
+            .mpd
+            .get_sticker(file, "playcount")?
+            .and_then(|plays| plays.parse::<u32>().ok())
+            .unwrap_or(0);
+        self.mpd
+            .set_sticker(file, "playcount", &(plays + 1).to_string())?;
+        self.mpd
+            .set_sticker(file, "lastplayed", &unix_time().to_string())?;
+        Ok(())
+    }
+
+    /// Returns the `playcount` sticker of every played song. Without a sticker
+    /// database, nothing has been played.
+    fn play_counts(&mut self) -> HashMap<String, u32> {
+        self.mpd
+            .find_stickers("playcount")
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|(file, plays)| Some((file, plays.parse().ok()?)))
+            .collect()
+    }
+
+    /// Returns the `rating` sticker of every rated song.
+    fn ratings(&mut self) -> HashMap<String, u8> {
+        self.mpd
+            .find_stickers("rating")
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|(file, rating)| Some((file, rating.parse().ok()?)))
+            .collect()
+    }
+
+    /// Aggregates the song stickers per album, for the albums of `artist` or all albums.
+    fn album_stats(
+        &mut self,
+        artist: Option<&str>,
+    ) -> Result<HashMap<(String, String), AlbumStats>, Box<dyn std::error::Error>> {
+        let tracks = self.mpd.list_tracks(artist, None)?;
+        Ok(self.album_stats_of(&tracks, true))
+    }
+
+    /// Aggregates the song stickers of `tracks` per album. Without `plays`, only
+    /// ratings are looked up.
+    fn album_stats_of(
+        &mut self,
+        tracks: &[Track],
+        plays: bool,
+    ) -> HashMap<(String, String), AlbumStats> {
+        let (play_counts, last_played) = if plays {
+            (
+                self.play_counts(),
+                self.mpd.find_stickers("lastplayed").unwrap_or_default(),
+            )
+        } else {
+            (HashMap::new(), HashMap::new())
+        };
+        let ratings = self.ratings();
+        let mut stats: HashMap<(String, String), AlbumStats> = HashMap::new();
+
+        for track in tracks {
+            let album_stats = stats
+                .entry((track.artist.clone(), track.album.clone()))
+                .or_default();
+            album_stats.plays += play_counts.get(&track.file).copied().unwrap_or(0);
+            if let Some(time) = last_played.get(&track.file).and_then(|t| t.parse().ok()) {
+                album_stats.last_played = album_stats.last_played.max(time);
+            }
+            if let Some(&rating) = ratings.get(&track.file) {
+                album_stats.rating_sum += rating as u32;
+                album_stats.rated_songs += 1;
+            }
+        }
+
+        stats
+    }
+
+    /// Removes the albums rated below `min_rating`, including unrated ones.
+    fn filter_albums_by_rating(
+        &mut self,
+        albums: &mut Vec<(String, String)>,
+        stats: &HashMap<(String, String), AlbumStats>,
+    ) {
+        if let Some(min_rating) = self.min_rating {
+            albums.retain(|album| {
+                stats
+                    .get(album)
+                    .and_then(AlbumStats::rating)
+                    .is_some_and(|rating| rating >= min_rating as f64)
+            });
+        }
+    }
+
+    fn rate_current(&mut self, whole_album: bool) -> Result<(), Box<dyn std::error::Error>> {
+        let Some(song) = self.mpd.current_song()? else {
+            self.report("No song is currently playing");
+            return Ok(());
+        };
+
+        let files: Vec<String> = if whole_album {
+            self.mpd
+                .list_tracks(Some(&song.artist), Some(&song.album))?
+                .into_iter()
+                .map(|track| track.file)
+                .collect()
+        } else {
+            vec![song.file.clone()]
+        };
+        let current_rating = self
+            .mpd
+            .get_sticker(&song.file, "rating")?
+            .and_then(|rating| rating.parse::<usize>().ok());
+
+        let items: Vec<String> = (0..=5).map(|rating| format_stars(rating as f64)).collect();
+        let prompt = if whole_album {
+            format!("Rate {}:", song.album)
+        } else {
+            format!("Rate {}:", song.title)
+        };
+        let Some((rating, _)) = self.rofi_select_index(
+            &items,
+            &[],
+            &prompt,
+            current_rating.unwrap_or(0).min(5),
+            false,
+            &[],
+        )?
+        else {
+            return Ok(());
+        };
+
+        for file in &files {
+            self.mpd.set_sticker(file, "rating", &rating.to_string())?;
+        }
+        if whole_album {
+            self.report_event(
+                &format!("Rated {} stars:\n{}\n{}", rating, song.artist, song.album),
+                Event {
+                    rating: Some(rating as u8),
+                    ..Event::album("rated", &song.artist, &song.album)
+                },
+            );
+        } else {
+            self.report_event(
+                &format!(
+                    "Rated {} stars:\n{}\n{}\n{}",
+                    rating, song.artist, song.album, song.title
+                ),
+                Event {
+                    rating: Some(rating as u8),
+                    file: Some(song.file.clone()),
+                    ..Event::song("rated", &song.artist, &song.album, &song.title)
+                },
+            );
+        }
+
+        Ok(())
+    }
+
+    fn play_song(
+        &mut self,
+        artist: &str,
+        album: Option<&str>,
+        title: &str,
+        queue_mode: bool,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        // If no album provided, find the album that contains this song
+        let actual_album = if let Some(album) = album {
+            Some(album.to_string())
+        } else {
+            self.mpd.find_song_album(artist, title)?
+        };
+        if !queue_mode {
+            Command::new("mpc").arg("clear").output()?;
+
+            // Build findadd command - add album filter if we found/have an album
+            let mut args = vec!["findadd"];
+            if let Some(ref album) = actual_album {
+                args.extend_from_slice(&["album", album]);
+            }
+            args.extend_from_slice(&["albumartist", artist]);
+
+            Command::new("mpc").args(&args).output()?;
+
+            let playlist = Command::new("mpc")
+                .args(["playlist", "-f", "%title%"])
+                .output()?;
+
+            let playlist_str = String::from_utf8_lossy(&playlist.stdout);
+            let songs: Vec<&str> = playlist_str.trim().split('\n').collect();
+
+            if let Some(position) = songs.iter().position(|&s| s == title) {
+                Command::new("mpc")
+                    .args(["play", &(position + 1).to_string()])
+                    .output()?;
+                let album = actual_album.as_deref().unwrap_or("");
+                self.report_event(
+                    &format!("Playing:\n{}\n{}\n{}", artist, album, title),
+                    Event::song("playing", artist, album, title),
+                );
+            } else {
+                Command::new("mpc").arg("play").output()?;
+                self.report(&format!("Could not find song '{}' in playlist", title));
+            }
+        } else {
+            // Queue the specific song
+            let mut args = vec!["findadd", "albumartist", artist];
+            if let Some(ref album) = actual_album {
+                args.extend_from_slice(&["album", album]);
+            }
+            args.extend_from_slice(&["title", title]);
+
+            Command::new("mpc").args(&args).output()?;
+            let album = actual_album.as_deref().unwrap_or("");
+            self.report_event(
+                &format!("Queued:\n{}\n{}\n{}", artist, album, title),
+                Event::song("queued", artist, album, title),
+            );
+        }
+
+        Ok(())
+    }
+
+    fn show_notification(&mut self, artist: &str, album: &str, title: Option<&str>) {
+        if self.notification.backend == NotificationBackend::None {
+            return;
+        }
+
+        let (default_summary, default_message) = if let Some(title) = title {
+            ("Now Playing", format!("{}\n{}\n{}", artist, album, title))
+        } else {
+            ("Now Playing Album", format!("{}\n{}", artist, album))
+        };
+        let fields = [
+            ("artist", artist),
+            ("album", album),
+            ("title", title.unwrap_or("")),
+        ];
+        let summary = match &self.notification.summary_template {
+            Some(template) => format_template(template, &fields),
+            None => default_summary.to_string(),
+        };
+        let message = match &self.notification.body_template {
+            Some(template) => format_template(template, &fields),
+            None => default_message,
+        };
+        let notification = Notification {
+            summary,
+            message,
+            image: self.cover_path(artist, album, None).ok().flatten(),
+            urgency: self.notification.urgency,
+            timeout_ms: NOTIFICATION_TIMEOUT_MS,
+            actions: if self.notification.actions {
+                PLAYBACK_ACTIONS
+            } else {
+                &[]
+            },
+        };
+
+        // Replace the previous notification instead of stacking a new one
+        let id_path = notification_id_path();
+        let replaces_id = id_path
+            .as_ref()
+            .and_then(|path| fs::read_to_string(path).ok())
+            .and_then(|id| id.trim().parse().ok())
+            .unwrap_or(0);
+
+        let result = match self.notification.backend {
+            NotificationBackend::Dbus => notify_dbus(&notification, replaces_id),
+            NotificationBackend::NotifySend => notify_send(&notification, replaces_id),
+            _ => notify_dbus(&notification, replaces_id)
+                .or_else(|_| notify_send(&notification, replaces_id)),
+        };
+
+        match result {
+            Ok((id, receiver)) => {
+                if let Some(path) = id_path {
+                    let _ = fs::write(path, id.to_string());
+                }
+                let Some(receiver) = receiver else {
+                    return;
+                };
+                // Wait for a click in the background so the caller carries on.
+                // The notification id is reused, so a click on a later
+                // notification also reaches the waiters of replaced ones.
+                let generation = self.notification_generation.fetch_add(1, Ordering::SeqCst) + 1;
+                let (artist, album) = (artist.to_string(), album.to_string());
+                let spawned = self.spawn_detached(move |selector| {
+                    let Ok(Some(action)) = receiver.recv_timeout(NOTIFICATION_ACTION_TIMEOUT)
+                    else {
+                        return;
+                    };
+                    if selector.notification_generation.load(Ordering::SeqCst) != generation {
+                        return;
+                    }
+                    let result = MpdClient::connect().and_then(|mpd| {
+                        // The connection may have timed out while waiting
+                        selector.mpd = mpd;
+                        selector.run_notification_action(&action, &artist, &album)
+                    });
+                    if let Err(err) = result {
+                        selector.report_error(&format!(
+                            "Notification action '{}' failed: {}",
+                            action, err
+                        ));
+                    }
+                });
+                if let Err(err) = spawned {
+                    self.report_error(&format!("Could not wait for notification actions: {}", err));
+                }
+            }
+            Err(err) => self.report_error(&format!("Could not show notification: {}", err)),
+        }
+    }
+
+    fn run_notification_action(
+        &mut self,
+        action: &str,
+        artist: &str,
+        album: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        match action {
+            "next" => {
+                Command::new("mpc").arg("next").output()?;
+            }
+            "queue-album" => self.queue_album(artist, album)?,
+            "quarantine" => self.add_list_album(QUARANTINE_LIST, artist, album)?,
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn play_random_album(
+        &mut self,
+        options: &RandomOptions,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(count) = options.songs {
+            return self.play_random_songs(count, options);
+        }
+
+        let mut albums = self.album_pool(&options.filter)?;
+        if self.min_rating.is_some() {
+            let stats = self.album_stats(None)?;
+            self.filter_albums_by_rating(&mut albums, &stats);
+        }
+        if albums.is_empty() {
+            self.report("No albums found");
+            return Ok(());
+        }
+
+        let picked = self.pick_random_albums(albums, options.albums.unwrap_or(1), options)?;
+        if picked.is_empty() {
+            self.report("No albums left outside the exclusion window");
+            return Ok(());
+        }
+
+        if !options.append {
+            Command::new("mpc").arg("clear").output()?;
+        }
+        for (artist, album) in &picked {
+            Command::new("mpc")
+                .args(["findadd", "album", album, "albumartist", artist])
+                .output()?;
+        }
+
+        let heading = match (options.append, picked.len()) {
+            (false, 1) => "Playing random album:",
+            (false, _) => "Playing random albums:",
+            (true, 1) => "Queued random album:",
+            (true, _) => "Queued random albums:",
+        };
+        let albums_text: Vec<String> = picked
+            .iter()
+            .map(|(artist, album)| format!("{}\n{}", artist, album))
+            .collect();
+        let event = if options.append { "queued" } else { "playing" };
+        let events = picked
+            .iter()
+            .map(|(artist, album)| Event::album(event, artist, album))
+            .collect();
+        self.report_events(&format!("{}\n{}", heading, albums_text.join("\n")), events);
+
+        if !options.append {
+            Command::new("mpc").arg("play").output()?;
+            let (artist, album) = &picked[0];
+            self.show_notification(artist, album, None);
+        }
+
+        Ok(())
+    }
+
+    fn play_random_songs(
+        &mut self,
+        count: usize,
+        options: &RandomOptions,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut songs = self.song_pool(&options.filter)?;
+        if self.min_rating.is_some() {
+            let ratings = self.ratings();
+            let min_rating = self.min_rating.unwrap_or(0);
+            songs.retain(|song| ratings.get(&song.file).is_some_and(|&r| r >= min_rating));
+        }
+        if songs.is_empty() {
+            self.report("No songs found");
+            return Ok(());
+        }
+
+        songs.sort_by(|a, b| a.file.cmp(&b.file));
+        let mut rng = random_generator(options.seed);
+        let picked: Vec<Track> = songs.choose_multiple(&mut rng, count).cloned().collect();
+
+        if !options.append {
+            Command::new("mpc").arg("clear").output()?;
+        }
+        Command::new("mpc")
+            .arg("add")
+            .args(picked.iter().map(|song| &song.file))
+            .output()?;
+        if !options.append {
+            Command::new("mpc").arg("play").output()?;
+        }
+
+        let heading = if options.append {
+            "Queued random songs:"
+        } else {
+            "Playing random songs:"
+        };
+        let songs_text: Vec<String> = picked
+            .iter()
+            .map(|song| format!("{} - {}", song.artist, song.title))
+            .collect();
+        let event = if options.append { "queued" } else { "playing" };
+        let events = picked
+            .iter()
+            .map(|song| Event {
+                file: Some(song.file.clone()),
+                ..Event::song(event, &song.artist, &song.album, &song.title)
+            })
+            .collect();
+        self.report_events(&format!("{}\n{}", heading, songs_text.join("\n")), events);
+
+        Ok(())
+    }
+
+    /// All albums matching the filter.
+    fn album_pool(
+        &mut self,
+        filter: &LibraryFilter,
+    ) -> Result<Vec<(String, String)>, Box<dyn std::error::Error>> {
+        let Some(expression) = filter.expression(self.mpd.supports_starts_with()) else {
+            return self.get_albums(None);
+        };
+
+        let mut albums = HashSet::new();
+        for track in self.mpd.search(&expression)? {
+            if !track.artist.is_empty() && !track.album.is_empty() {
+                albums.insert((track.artist, track.album));
+            }
+        }
+
+        Ok(albums.into_iter().collect())
+    }
+
+    /// All songs matching the filter.
+    fn song_pool(
+        &mut self,
+        filter: &LibraryFilter,
+    ) -> Result<Vec<Track>, Box<dyn std::error::Error>> {
+        match filter.expression(self.mpd.supports_starts_with()) {
+            Some(expression) => self.mpd.search(&expression),
+            None => self.mpd.list_tracks(None, None),
+        }
+    }
+
+    /// Picks up to `count` different random albums, honouring the exclusion
+    /// window and weighting of `options`.
+    fn pick_random_albums(
+        &mut self,
+        mut albums: Vec<(String, String)>,
+        count: usize,
+        options: &RandomOptions,
+    ) -> Result<Vec<(String, String)>, Box<dyn std::error::Error>> {
+        // The album list comes from a hash set, sort it so seeded picks are reproducible
+        albums.sort();
+        let mut rng = random_generator(options.seed);
+
+        if !options.weighted && options.exclude_days.is_none() {
+            return Ok(albums.choose_multiple(&mut rng, count).cloned().collect());
+        }
+
+        let stats = self.album_listening_stats()?;
+        let now = unix_time();
+        let days_since_played = |album: &(String, String)| {
+            stats
+                .get(album)
+                .filter(|stats| stats.last_played > 0)
+                .map(|stats| now.saturating_sub(stats.last_played) / 86400)
+        };
+
+        if let Some(exclude_days) = options.exclude_days {
+            albums.retain(|album| days_since_played(album).is_none_or(|days| days >= exclude_days));
+        }
+        if !options.weighted {
+            return Ok(albums.choose_multiple(&mut rng, count).cloned().collect());
+        }
+
+        let mut weights: Vec<f64> = albums
+            .iter()
+            .map(|album| {
+                let plays = stats.get(album).map(|stats| stats.plays).unwrap_or(0);
+                let days = days_since_played(album)
+                    .unwrap_or(WEIGHT_MAX_DAYS)
+                    .min(WEIGHT_MAX_DAYS);
+                (days + 1) as f64 / (plays + 1) as f64
+            })
+            .collect();
+
+        let mut picked = Vec::new();
+        while picked.len() < count && !albums.is_empty() {
+            let index = WeightedIndex::new(&weights)?.sample(&mut rng);
+            weights.remove(index);
+            picked.push(albums.remove(index));
+        }
+
+        Ok(picked)
+    }
+
+    /// Album statistics from the stickers, with the last play times completed
+    /// from the local history log.
+    fn album_listening_stats(
+        &mut self,
+    ) -> Result<HashMap<(String, String), AlbumStats>, Box<dyn std::error::Error>> {
+        let mut stats = self.album_stats(None)?;
+
+        for entry in self.load_history()? {
+            if entry.listened < PLAY_COUNT_THRESHOLD {
+                continue;
+            }
+            let album_stats = stats.entry((entry.artist, entry.album)).or_default();
+            album_stats.last_played = album_stats.last_played.max(entry.timestamp);
+        }
+
+        Ok(stats)
+    }
+
+    fn load_list_albums(
+        &self,
+        name: &str,
+    ) -> Result<Vec<(String, String)>, Box<dyn std::error::Error>> {
+        Ok(self
+            .load_list_entries(name)?
+            .into_iter()
+            .map(|entry| (entry.artist, entry.album))
+            .collect())
+    }
+
+    fn load_list_entries(&self, name: &str) -> Result<Vec<ListEntry>, Box<dyn std::error::Error>> {
+        let path = list_path(name)?;
+
+        if !path.exists() {
+            self.report(&format!("List file not found: {}", path.display()));
+            return Ok(Vec::new());
+        }
+
+        Ok(self
+            .load_list(name)?
+            .entries()
+            .into_iter()
+            .cloned()
+            .collect())
+    }
+
+    /// Loads a list file, reporting lines that could not be parsed.
+    fn load_list(&self, name: &str) -> Result<AlbumList, Box<dyn std::error::Error>> {
+        let list = AlbumList::load(list_path(name)?)?;
+        for error in &list.errors {
+            self.report_error(error);
+        }
+
+        Ok(list)
+    }
+
+    fn add_list_album(
+        &self,
+        name: &str,
+        artist: &str,
+        album: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut list = self.load_list(name)?;
+        let entry = ListEntry {
+            added: Some(format_date(unix_time())),
+            ..ListEntry::new(artist, album)
+        };
+        if !list.add(entry) {
+            self.report(&format!("Already in {}:\n{}\n{}", name, artist, album));
+            return Ok(());
+        }
+
+        list.save()?;
+        self.report_event(
+            &format!("Added to {}:\n{}\n{}", name, artist, album),
+            Event {
+                list: Some(name.to_string()),
+                ..Event::album("added", artist, album)
+            },
+        );
+
+        Ok(())
+    }
+
+    fn run_list_action(
+        &mut self,
+        name: &str,
+        action: Option<ListAction>,
+        preselect: usize,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        match action {
+            None => self.navigate(
+                Menu::ListAlbum {
+                    name: name.to_string(),
+                },
+                preselect,
+            ),
+            Some(ListAction::Add { artist, album }) => self.list_add(name, artist, album),
+            Some(ListAction::Remove) => self.list_remove(name),
+            Some(ListAction::List) => self.list_print(name),
+            Some(ListAction::Check { fix }) => self.list_check(name, fix),
+            Some(ListAction::ImportNew { since }) => self.list_import_new(name, since.as_deref()),
+            Some(ListAction::Graduate { sticker }) => self.list_graduate(name, sticker),
+        }
+    }
+
+    /// Adds the given album, or the album of the current song, to a list.
+    fn list_add(
+        &mut self,
+        name: &str,
+        artist: Option<String>,
+        album: Option<String>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let (artist, album) = match (artist, album) {
+            (Some(artist), Some(album)) => (artist, album),
+            _ => match self.mpd.current_song()? {
+                Some(song) if !song.artist.is_empty() && !song.album.is_empty() => {
+                    (song.artist, song.album)
+                }
+                _ => return Err("No album is currently playing".into()),
+            },
+        };
+
+        self.add_list_album(name, &artist, &album)
+    }
+
+    fn list_remove(&mut self, name: &str) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some((artist, album, _)) = self.select_list_album(name, false, None)? {
+            self.remove_list_album(name, &artist, &album)?;
+        }
+
+        Ok(())
+    }
+
+    fn remove_list_album(
+        &self,
+        name: &str,
+        artist: &str,
+        album: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut list = self.load_list(name)?;
+        if list.remove(artist, album) {
+            list.save()?;
+            self.report_event(
+                &format!("Removed from {}:\n{}\n{}", name, artist, album),
+                Event {
+                    list: Some(name.to_string()),
+                    ..Event::album("removed", artist, album)
+                },
+            );
+        }
+
+        Ok(())
+    }
+
+    fn list_print(&self, name: &str) -> Result<(), Box<dyn std::error::Error>> {
+        for entry in self.load_list_entries(name)? {
+            self.report_event(
+                &format!("{}\t{}", entry.artist, entry.album),
+                Event {
+                    list: Some(name.to_string()),
+                    added: entry.added,
+                    note: entry.note,
+                    ..Event::album("list-entry", &entry.artist, &entry.album)
+                },
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Resolves every entry of a list against the library and reports entries
+    /// that don't match exactly, with suggestions. With `fix`, entries matching
+    /// exactly one album up to case and punctuation are rewritten to its tags.
+    fn list_check(&mut self, name: &str, fix: bool) -> Result<(), Box<dyn std::error::Error>> {
+        let mut list = self.load_list(name)?;
+        let library = self.mpd.list_albums(None)?;
+        let library_set: HashSet<&(String, String)> = library.iter().collect();
+
+        let mut unresolved = 0;
+        let mut fixed = 0;
+        for (artist, album) in list.albums() {
+            if library_set.contains(&(artist.clone(), album.clone())) {
+                continue;
+            }
+
+            let normalized = (normalize_tag(&artist), normalize_tag(&album));
+            let mut candidates: Vec<(String, String)> = library
+                .iter()
+                .filter(|(a, b)| (normalize_tag(a), normalize_tag(b)) == normalized)
+                .cloned()
+                .collect();
+            // Several albums differing only in case or punctuation
+            let ambiguous = candidates.len() > 1;
+            // Only these are certain enough to fix, near matches are suggestions
+            let normalized_match = !candidates.is_empty();
+            if candidates.is_empty() {
+                candidates = near_matches(
+                    &format!("{}\t{}", artist, album),
+                    &library,
+                    |(a, b)| format!("{}\t{}", a, b),
+                    3,
+                );
+            }
+
+            if let [(new_artist, new_album)] = candidates.as_slice() {
+                if fix && normalized_match {
+                    if list.contains(new_artist, new_album) {
+                        list.remove(&artist, &album);
+                    } else {
+                        list.rename(&artist, &album, new_artist, new_album);
+                    }
+                    fixed += 1;
+                    self.report(&format!(
+                        "Fixed: {} - {} -> {} - {}",
+                        artist, album, new_artist, new_album
+                    ));
+                    continue;
+                }
+            }
+
+            let mut message = format!(
+                "{}: {} - {}",
+                if ambiguous { "Ambiguous" } else { "Missing" },
+                artist,
+                album
+            );
+            for (a, b) in &candidates {
+                message.push_str(&format!("\n  did you mean: {} - {}", a, b));
+            }
+            self.report(&message);
+            unresolved += 1;
+        }
+
+        if fixed > 0 {
+            list.save()?;
+        }
+        if unresolved > 0 {
+            return Err(
+                format!("{} entries of {} don't match the library", unresolved, name).into(),
+            );
+        }
+        self.report(&format!("All entries of {} match the library", name));
+
+        Ok(())
+    }
+
+    /// Returns the albums with files modified after the unix time `since`.
+    fn albums_modified_since(
+        &mut self,
+        since: u64,
+    ) -> Result<Vec<(String, String)>, Box<dyn std::error::Error>> {
+        let filter = format!("(modified-since '{}')", since);
+        let mut albums: Vec<(String, String)> = Vec::new();
+        for track in self.mpd.search(&filter)? {
+            let album = (track.artist, track.album);
+            if !album.0.is_empty() && !album.1.is_empty() && !albums.contains(&album) {
+                albums.push(album);
+            }
+        }
+        Ok(albums)
+    }
+
+    /// Drops the cached covers of albums changed since the last check. Without
+    /// a record of the last check, the whole cache is dropped.
+    fn invalidate_covers(&mut self) -> Result<usize, Box<dyn std::error::Error>> {
+        let cover_dir = cache_dir()?.join("covers");
+        let checked_path = cache_dir()?.join("covers.checked");
+        let since = fs::read_to_string(&checked_path)
+            .ok()
+            .and_then(|time| time.trim().parse::<u64>().ok());
+        let now = unix_time();
+
+        let mut invalidated = 0;
+        match since {
+            Some(since) => {
+                for (artist, album) in self.albums_modified_since(since)? {
+                    let key = cover_key(&artist, &album);
+                    for extension in COVER_EXTENSIONS.iter().chain(&["none"]) {
+                        match fs::remove_file(cover_dir.join(format!("{}.{}", key, extension))) {
+                            Ok(()) => invalidated += 1,
+                            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+                            Err(err) => return Err(err.into()),
+                        }
+                    }
+                }
+            }
+            None if cover_dir.exists() => {
+                invalidated = fs::read_dir(&cover_dir)?.count();
+                fs::remove_dir_all(&cover_dir)?;
+            }
+            None => {}
+        }
+
+        fs::create_dir_all(cache_dir()?)?;
+        fs::write(&checked_path, now.to_string())?;
+        Ok(invalidated)
+    }
+
+    /// Adds every album with files modified after `since`, or after the last
+    /// import into this list, to the list. The first run only remembers the time.
+    fn list_import_new(
+        &mut self,
+        name: &str,
+        since: Option<&str>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let last_import_path = last_import_path(name)?;
+        let since = match since {
+            Some(date) => Some(parse_date(date).ok_or_else(|| format!("Invalid date '{}'", date))?),
+            None => fs::read_to_string(&last_import_path)
+                .ok()
+                .and_then(|time| time.trim().parse::<u64>().ok()),
+        };
+        let now = unix_time();
+
+        let mut imported = Vec::new();
+        if let Some(since) = since {
+            let albums = self.albums_modified_since(since)?;
+            let mut list = self.load_list(name)?;
+            let added = format_date(now);
+            for (artist, album) in albums {
+                let entry = ListEntry {
+                    added: Some(added.clone()),
+                    source: Some("import-new".to_string()),
+                    ..ListEntry::new(&artist, &album)
+                };
+                if list.add(entry) {
+                    imported.push((artist, album));
+                }
+            }
+            if !imported.is_empty() {
+                list.save()?;
+            }
+        }
+
+        if let Some(dir) = last_import_path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(&last_import_path, now.to_string())?;
+
+        match (since, imported.is_empty()) {
+            (None, _) => self.report(&format!(
+                "First import into {}: only new albums from now on will be added",
+                name
+            )),
+            (Some(_), true) => self.report(&format!("No new albums for {}", name)),
+            (Some(_), false) => {
+                let albums: Vec<String> = imported
+                    .iter()
+                    .map(|(artist, album)| format!("{}\n{}", artist, album))
+                    .collect();
+                let events = imported
+                    .iter()
+                    .map(|(artist, album)| Event {
+                        list: Some(name.to_string()),
+                        ..Event::album("added", artist, album)
+                    })
+                    .collect();
+                self.report_events(
+                    &format!("Added to {}:\n{}", name, albums.join("\n")),
+                    events,
+                )
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Removes a listened-to album from a list, optionally marking its songs
+    /// with a `graduated` sticker holding the date.
+    fn list_graduate(
+        &mut self,
+        name: &str,
+        sticker: bool,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let Some((artist, album, _)) = self.select_list_album(name, false, None)? else {
+            return Ok(());
+        };
+
+        self.graduate_album(name, &artist, &album, sticker)
+    }
+
+    fn graduate_album(
+        &mut self,
+        name: &str,
+        artist: &str,
+        album: &str,
+        sticker: bool,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut list = self.load_list(name)?;
+        if !list.remove(artist, album) {
+            return Err(format!("Not in {}: {} - {}", name, artist, album).into());
+        }
+        list.save()?;
+
+        if sticker {
+            let date = format_date(unix_time());
+            for track in self.mpd.list_tracks(Some(artist), Some(album))? {
+                self.mpd.set_sticker(&track.file, "graduated", &date)?;
+            }
+        }
+        self.report_event(
+            &format!("Graduated from {}:\n{}\n{}", name, artist, album),
+            Event {
+                list: Some(name.to_string()),
+                ..Event::album("graduated", artist, album)
+            },
+        );
+
+        Ok(())
+    }
+
+    fn select_list_album(
+        &mut self,
+        name: &str,
+        random_mode: bool,
+        preselect: Option<(&str, &str)>,
+    ) -> Result<Option<AlbumSelection>, Box<dyn std::error::Error>> {
+        let mut entries = self.load_list_entries(name)?;
+        if entries.is_empty() {
+            self.report(&format!("No albums found in {}", name));
+            return Ok(None);
+        }
+
+        if random_mode {
+            let entry = entries.choose(&mut rand::thread_rng()).unwrap();
+            return Ok(Some((entry.artist.clone(), entry.album.clone(), false)));
+        }
+
+        let history = self.load_history()?;
+        let mut rows = Vec::new();
+        for entry in entries.drain(..) {
+            let files: Vec<String> = self
+                .mpd
+                .list_tracks(Some(&entry.artist), Some(&entry.album))?
+                .into_iter()
+                .map(|track| track.file)
+                .collect();
+            let listens = album_listens(&history, &files);
+            let added = entry.added.as_deref().and_then(parse_date);
+            rows.push((entry, added, listens));
+        }
+        match self.list_sort {
+            ListSort::File => {}
+            ListSort::Random => rows.shuffle(&mut rand::thread_rng()),
+            // Entries without a date predate dates in the file, so they come first
+            ListSort::Oldest => rows.sort_by_key(|(_, added, _)| *added),
+            ListSort::Newest => rows.sort_by_key(|(_, added, _)| std::cmp::Reverse(*added)),
+            ListSort::FewestListens => rows.sort_by_key(|(_, _, listens)| *listens),
+        }
+
+        let today = unix_time() / 86400;
+        let items: Vec<String> = rows
+            .iter()
+            .map(|(entry, added, listens)| {
+                let age = added
+                    .map(|added| format_age(today.saturating_sub(added / 86400)))
+                    .unwrap_or_default();
+                [
+                    entry.artist.as_str(),
+                    entry.album.as_str(),
+                    &age,
+                    &format_listens(*listens),
+                    entry.note.as_deref().unwrap_or(""),
+                ]
+                .join("\t")
+            })
+            .collect();
+        let albums: Vec<(String, String)> = rows
+            .iter()
+            .map(|(entry, _, _)| (entry.artist.clone(), entry.album.clone()))
+            .collect();
+
+        let selected_row = preselect
+            .and_then(|(artist, album)| albums.iter().position(|(a, b)| a == artist && b == album))
+            .unwrap_or(0);
+        let icons = self.album_icons(&albums);
+        let mut chars = name.chars();
+        let prompt = match chars.next() {
+            Some(first) => format!("{}{} Album:", first.to_uppercase(), chars.as_str()),
+            None => "Album:".to_string(),
+        };
+        if let Some((index, exit_code)) =
+            self.rofi_select_index(&items, &icons, &prompt, selected_row, true, &[])?
+        {
+            let (artist, album) = &albums[index];
+            return Ok(Some((artist.clone(), album.clone(), exit_code == 10)));
+        }
+
+        Ok(None)
+    }
+
+    fn play_random_list_album(&mut self, name: &str) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some((artist, album, _)) = self.select_list_album(name, true, None)? {
+            // Leave the queue alone if the entry doesn't match the library
+            if self.mpd.first_album_file(&artist, &album)?.is_none() {
+                return Err(format!(
+                    "No songs found for {} album {} - {} (see `list {} check`)",
+                    name, artist, album, name
+                )
+                .into());
+            }
+            Command::new("mpc").arg("clear").output()?;
+            Command::new("mpc")
+                .args(["findadd", "album", &album, "albumartist", &artist])
+                .output()?;
+            Command::new("mpc").arg("play").output()?;
+
+            self.report_event(
+                &format!("Playing random {} album:\n{}\n{}", name, artist, album),
+                Event {
+                    list: Some(name.to_string()),
+                    ..Event::album("playing", &artist, &album)
+                },
+            );
+            self.show_notification(&artist, &album, None);
+        }
+
+        Ok(())
+    }
+
+    fn show_playlist(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let playlist = self.mpd.get_playlist()?;
+        if playlist.is_empty() {
+            self.report("Playlist is empty");
+            return Ok(());
+        }
+
+        let status = self.mpd.get_status()?;
+        let current_pos = status
+            .get("song")
+            .and_then(|s| s.parse::<usize>().ok())
+            .unwrap_or(0);
+
+        // Positions in the playlist of the tracks shown in the menu
+        let positions: Vec<usize> = (0..playlist.len())
+            .filter(|&i| !playlist[i].artist.is_empty() && !playlist[i].title.is_empty())
+            .collect();
+
+        let playlist_items: Vec<String> = positions
+            .iter()
+            .map(|&i| {
+                let track = &playlist[i];
+                let artist = &track.artist;
+                let title = &track.title;
+
+                let display_title = if let Some(track_num) = &track.track {
+                    let track_num = track_num.split('/').next().unwrap_or(track_num);
+                    if !track_num.is_empty() {
+                        format!("{:02} {}", track_num.parse::<u32>().unwrap_or(0), title)
+                    } else {
+                        title.to_string()
+                    }
+                } else {
+                    title.to_string()
+                };
+
+                format!("{}\t{}", artist, display_title)
+            })
+            .collect();
+
+        let icons = if self.show_icons {
+            let mut fetches = 0;
+            positions
+                .iter()
+                .map(|&i| {
+                    let track = &playlist[i];
+                    self.menu_icon(&track.artist, &track.album, Some(&track.file), &mut fetches)
+                })
+                .collect()
+        } else {
+            Vec::new()
+        };
+        let selected_row = positions
+            .iter()
+            .position(|&i| i == current_pos)
+            .unwrap_or(0);
+
+        let (selected_display, _) =
+            self.rofi_select_with_icons(&playlist_items, &icons, "Playlist:", selected_row, true)?;
+
+        if let Some(selected) = selected_display {
+            if let Some(index) = playlist_items.iter().position(|x| x == &selected) {
+                let position = positions[index];
+                Command::new("mpc")
+                    .args(["play", &(position + 1).to_string()])
+                    .output()?;
+
+                let track = &playlist[position];
+                let artist = if track.artist.is_empty() {
+                    "Unknown Artist"
+                } else {
+                    &track.artist
+                };
+                let album = if track.album.is_empty() {
+                    "Unknown Album"
+                } else {
+                    &track.album
+                };
+                let title = if track.title.is_empty() {
+                    "Unknown Title"
+                } else {
+                    &track.title
+                };
+
+                self.emit(&Event {
+                    file: Some(track.file.clone()),
+                    ..Event::song("playing", artist, album, title)
+                });
+                self.show_notification(artist, album, Some(title));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn browse(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let mut current = String::new();
+        let mut previous: Option<String> = None;
+
+        loop {
+            let entries = self.mpd.list_directory(&current)?;
+            let mut items = Vec::new();
+            if !current.is_empty() {
+                items.push("..".to_string());
+            }
+            let offset = items.len();
+            items.extend(entries.iter().map(|entry| match entry {
+                DirEntry::Directory(path) => format!("{}/", path_name(path)),
+                DirEntry::File(path) => path_name(path).to_string(),
+            }));
+
+            // When going up, preselect the directory we just came from
+            let selected_row = previous
+                .take()
+                .and_then(|previous| {
+                    entries.iter().position(
+                        |entry| matches!(entry, DirEntry::Directory(path) if *path == previous),
+                    )
+                })
+                .map(|index| index + offset)
+                .unwrap_or(0);
+
+            let prompt = if current.is_empty() {
+                "Browse:".to_string()
+            } else {
+                format!("{}:", current)
+            };
+            let Some((index, exit_code)) = self.rofi_select_index(
+                &items,
+                &[],
+                &prompt,
+                selected_row,
+                false,
+                &[
+                    "-kb-custom-2",
+                    "Alt+Return",
+                    "-mesg",
+                    "Enter: play    Ctrl+Enter: queue    Alt+Enter: open directory",
+                ],
+            )?
+            else {
+                return Ok(());
+            };
+
+            if index < offset {
+                previous = Some(current.clone());
+                current = parent_path(&current).to_string();
+                continue;
+            }
+
+            match &entries[index - offset] {
+                DirEntry::Directory(path) => match exit_code {
+                    11 => current = path.clone(),
+                    10 => {
+                        Command::new("mpc").args(["add", path]).output()?;
+                        self.report_event(
+                            &format!("Queued:\n{}", path),
+                            Event::file("queued", path),
+                        );
+                        return Ok(());
+                    }
+                    _ => {
+                        Command::new("mpc").arg("clear").output()?;
+                        Command::new("mpc").args(["add", path]).output()?;
+                        Command::new("mpc").arg("play").output()?;
+                        self.report_event(
+                            &format!("Playing:\n{}", path),
+                            Event::file("playing", path),
+                        );
+                        return Ok(());
+                    }
+                },
+                DirEntry::File(path) => {
+                    if exit_code == 10 {
+                        Command::new("mpc").args(["add", path]).output()?;
+                        self.report_event(
+                            &format!("Queued:\n{}", path),
+                            Event::file("queued", path),
+                        );
+                    } else {
+                        self.play_file_in_directory(&entries, path)?;
+                    }
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    /// Replaces the queue with the files of one directory level and plays
+    /// `file` among them. Subdirectories are left out.
+    fn play_file_in_directory(
+        &mut self,
+        entries: &[DirEntry],
+        file: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let files: Vec<&str> = entries
+            .iter()
+            .filter_map(|entry| match entry {
+                DirEntry::File(path) => Some(path.as_str()),
+                DirEntry::Directory(_) => None,
+            })
+            .collect();
+        let position = files.iter().position(|&f| f == file).unwrap_or(0);
+
+        Command::new("mpc").arg("clear").output()?;
+        Command::new("mpc").arg("add").args(&files).output()?;
+        Command::new("mpc")
+            .args(["play", &(position + 1).to_string()])
+            .output()?;
+        self.report_event(&format!("Playing:\n{}", file), Event::file("playing", file));
+
+        Ok(())
+    }
+
+    fn select_stored_playlist(
+        &mut self,
+        prompt: &str,
+    ) -> Result<(Option<String>, bool), Box<dyn std::error::Error>> {
+        let playlists = self.mpd.list_playlists()?;
+        if playlists.is_empty() {
+            self.report("No stored playlists found");
+            return Ok((None, false));
+        }
+
+        self.rofi_select(&playlists, prompt, 0, false)
+    }
+
+    fn load_stored_playlist(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        if let (Some(name), queue_mode) = self.select_stored_playlist("Playlist:")? {
+            if queue_mode {
+                Command::new("mpc").args(["load", &name]).output()?;
+                self.report_event(
+                    &format!("Queued playlist:\n{}", name),
+                    Event {
+                        playlist: Some(name.clone()),
+                        ..Event::new("queued")
+                    },
+                );
+            } else {
+                Command::new("mpc").arg("clear").output()?;
+                Command::new("mpc").args(["load", &name]).output()?;
+                Command::new("mpc").arg("play").output()?;
+                self.report_event(
+                    &format!("Playing playlist:\n{}", name),
+                    Event {
+                        playlist: Some(name.clone()),
+                        ..Event::new("playing")
+                    },
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    fn manage_stored_playlists(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let actions = vec![
+            "Save current queue as…".to_string(),
+            "Add current song to playlist…".to_string(),
+            "Delete playlist".to_string(),
+        ];
+
+        let Some((action, _)) =
+            self.rofi_select_index(&actions, &[], "Playlists:", 0, false, &[])?
+        else {
+            return Ok(());
+        };
+
+        match action {
+            0 => {
+                if let Some(name) = self.rofi_input("Save queue as:")? {
+                    self.mpd.save_playlist(&name)?;
+                    self.report_event(
+                        &format!("Saved queue as playlist:\n{}", name),
+                        Event {
+                            playlist: Some(name.clone()),
+                            ..Event::new("saved-playlist")
+                        },
+                    );
+                }
+            }
+            1 => {
+                let Some(song) = self.mpd.current_song()? else {
+                    self.report("No song is currently playing");
+                    return Ok(());
+                };
+                if let (Some(name), _) = self.select_stored_playlist("Add to playlist:")? {
+                    self.mpd.add_to_playlist(&name, &song.file)?;
+                    self.report_event(
+                        &format!("Added to playlist {}:\n{}", name, song.file),
+                        Event {
+                            playlist: Some(name.clone()),
+                            ..Event::file("added", &song.file)
+                        },
+                    );
+                }
+            }
+            _ => {
+                if let (Some(name), _) = self.select_stored_playlist("Delete playlist:")? {
+                    let confirm = vec!["No".to_string(), "Yes".to_string()];
+                    let prompt = format!("Delete {}?", name);
+                    if let (Some(answer), _) = self.rofi_select(&confirm, &prompt, 0, false)? {
+                        if answer == "Yes" {
+                            self.mpd.delete_playlist(&name)?;
+                            self.report_event(
+                                &format!("Deleted playlist:\n{}", name),
+                                Event {
+                                    playlist: Some(name.clone()),
+                                    ..Event::new("deleted-playlist")
+                                },
+                            );
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn queue_album(&self, artist: &str, album: &str) -> Result<(), Box<dyn std::error::Error>> {
+        Command::new("mpc")
+            .args(["findadd", "album", album, "albumartist", artist])
+            .output()?;
+        self.report_event(
+            &format!("Queued album:\n{}\n{}", artist, album),
+            Event::album("queued", artist, album),
+        );
+        Ok(())
+    }
+
+    /// Plays or queues an album or song given by its exact tags. Fails with
+    /// suggestions if a name doesn't match the library.
+    fn play_target(
+        &mut self,
+        target: &PlayTarget,
+        queue_mode: bool,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let artists = self.mpd.list_artists()?;
+        require_match("Artist", &target.artist, &artists)?;
+
+        let albums: Vec<String> = self
+            .mpd
+            .list_albums(Some(&target.artist))?
+            .into_iter()
+            .map(|(_, album)| album)
+            .collect();
+        require_match("Album", &target.album, &albums)?;
+
+        if let Some(title) = &target.title {
+            let titles: Vec<String> = self
+                .mpd
+                .list_tracks(Some(&target.artist), Some(&target.album))?
+                .into_iter()
+                .map(|track| track.title)
+                .collect();
+            require_match("Song", title, &titles)?;
+            return self.play_song(&target.artist, Some(&target.album), title, queue_mode);
+        }
+
+        if queue_mode {
+            return self.queue_album(&target.artist, &target.album);
+        }
+        Command::new("mpc").arg("clear").output()?;
+        Command::new("mpc")
+            .args([
+                "findadd",
+                "album",
+                &target.album,
+                "albumartist",
+                &target.artist,
+            ])
+            .output()?;
+        Command::new("mpc").arg("play").output()?;
+        self.report_event(
+            &format!("Playing album:\n{}\n{}", target.artist, target.album),
+            Event::album("playing", &target.artist, &target.album),
+        );
+        self.show_notification(&target.artist, &target.album, None);
+
+        Ok(())
+    }
+
+    /// Prints library queries to stdout, one line per item formatted with a
+    /// `%field%` template. `\t` and `\n` in the template are unescaped.
+    fn print_library(&mut self, target: LsTarget) -> Result<(), Box<dyn std::error::Error>> {
+        let unescape = |format: &str| format.replace("\\t", "\t").replace("\\n", "\n");
+
+        match target {
+            LsTarget::Artists { format } => {
+                let format = unescape(&format);
+                for artist in self.mpd.list_artists()? {
+                    self.report_event(
+                        &format_template(&format, &[("artist", &artist)]),
+                        Event {
+                            artist: Some(artist.clone()),
+                            ..Event::new("artist")
+                        },
+                    );
+                }
+            }
+            LsTarget::Albums { artist, format } => {
+                let format = unescape(&format);
+                let mut albums = self.mpd.list_albums(artist.as_deref())?;
+                albums.sort();
+                for (artist, album) in albums {
+                    self.report_event(
+                        &format_template(&format, &[("artist", &artist), ("album", &album)]),
+                        Event::album("album", &artist, &album),
+                    );
+                }
+            }
+            LsTarget::Songs {
+                artist,
+                album,
+                format,
+            } => {
+                let format = unescape(&format);
+                for track in self.mpd.list_tracks(artist.as_deref(), album.as_deref())? {
+                    let number = track.track.clone().unwrap_or_default();
+                    let fields = [
+                        ("artist", track.artist.as_str()),
+                        ("album", track.album.as_str()),
+                        ("title", track.title.as_str()),
+                        ("track", number.as_str()),
+                        ("file", track.file.as_str()),
+                    ];
+                    self.report_event(
+                        &format_template(&format, &fields),
+                        Event {
+                            file: Some(track.file.clone()),
+                            ..Event::song("song", &track.artist, &track.album, &track.title)
+                        },
+                    );
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn search_library(
+        &mut self,
+        query: Option<&str>,
+        preselect_index: usize,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let query = match query {
+            Some(query) => query.to_string(),
+            None => match self.rofi_input("Search:")? {
+                Some(query) => query,
+                None => return Ok(()),
+            },
+        };
+
+        let filter = parse_search_query(&query)?;
+        let tracks: Vec<Track> = self
+            .mpd
+            .search(&filter)?
+            .into_iter()
+            .filter(|track| !track.artist.is_empty() && !track.title.is_empty())
+            .collect();
+        if tracks.is_empty() {
+            self.report(&format!("No results for '{}'", query));
+            return Ok(());
+        }
+
+        let mut albums: Vec<(String, String)> = Vec::new();
+        for track in &tracks {
+            let key = (track.artist.clone(), track.album.clone());
+            if !track.album.is_empty() && !albums.contains(&key) {
+                albums.push(key);
+            }
+        }
+
+        self.select_album_or_song(&format!("{}:", query), &albums, &tracks, preselect_index)
+    }
+
+    /// Shows albums followed by songs in one menu and plays or queues the selection.
+    fn select_album_or_song(
+        &mut self,
+        prompt: &str,
+        albums: &[(String, String)],
+        tracks: &[Track],
+        preselect_index: usize,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let items: Vec<String> = albums
+            .iter()
+            .map(|(artist, album)| format!("Album\t{}\t{}", artist, album))
+            .chain(
+                tracks
+                    .iter()
+                    .map(|track| format!("Song\t{}\t{}", track.artist, track.title)),
+            )
+            .collect();
+
+        let Some((index, exit_code)) = self.rofi_select_index(&items, &[], prompt, 0, true, &[])?
+        else {
+            return Ok(());
+        };
+        let queue_mode = exit_code == 10;
+
+        if let Some((artist, album)) = albums.get(index) {
+            if queue_mode {
+                self.queue_album(artist, album)?;
+            } else if let Some((title, song_queue_mode)) =
+                self.select_song(Some(artist), Some(album), preselect_index)?
+            {
+                self.play_song(artist, Some(album), &title, song_queue_mode)?;
+            }
+        } else {
+            let track = &tracks[index - albums.len()];
+            let album = (!track.album.is_empty()).then_some(track.album.as_str());
+            self.play_song(&track.artist, album, &track.title, queue_mode)?;
+        }
+
+        Ok(())
+    }
+
+    fn select_from_history(
+        &mut self,
+        limit: usize,
+        preselect_index: usize,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let history = self.load_history()?;
+        if history.is_empty() {
+            self.report("No listening history yet");
+            return Ok(());
+        }
+
+        // Most recent first, each album and song only once
+        let mut albums: Vec<(String, String)> = Vec::new();
+        let mut tracks: Vec<Track> = Vec::new();
+        for entry in history.iter().rev() {
+            if entry.artist.is_empty() || entry.title.is_empty() {
+                continue;
+            }
+            let key = (entry.artist.clone(), entry.album.clone());
+            if !entry.album.is_empty() && !albums.contains(&key) && albums.len() < limit {
+                albums.push(key);
+            }
+            if !tracks.iter().any(|track| track.file == entry.file) && tracks.len() < limit {
+                tracks.push(Track {
+                    artist: entry.artist.clone(),
+                    album: entry.album.clone(),
+                    title: entry.title.clone(),
+                    track: None,
+                    file: entry.file.clone(),
+                });
+            }
+        }
+
+        self.select_album_or_song("Recently played:", &albums, &tracks, preselect_index)
+    }
+
+    /// Runs the hierarchical menus starting at `root`. Escape in a menu goes back
+    /// to the previous one with the earlier choice preselected.
+    fn navigate(
+        &mut self,
+        root: Menu,
+        preselect_index: usize,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut stack = vec![root];
+        // The menu we just went back from; it encodes the choice made in the current menu
+        let mut back_from: Option<Menu> = None;
+
+        while let Some(menu) = stack.last().cloned() {
+            let previous = back_from.take();
+            let preselect_album = match &previous {
+                Some(Menu::Song { artist, album }) => Some((artist.as_str(), album.as_str())),
+                _ => None,
+            };
+
+            let next = match menu {
+                Menu::Artist => {
+                    let preselect = match &previous {
+                        Some(Menu::Album {
+                            artist: Some(artist),
+                        }) => Some(artist.as_str()),
+                        _ => None,
+                    };
+                    self.select_artist(preselect)?.map(|artist| {
+                        self.emit(&Event {
+                            artist: Some(artist.clone()),
+                            ..Event::new("selected")
+                        });
+                        Menu::Album {
+                            artist: Some(artist),
+                        }
+                    })
+                }
+                Menu::Album { artist } => {
+                    match self.select_album(artist.as_deref(), preselect_album)? {
+                        Some((artist, album, true)) => return self.queue_album(&artist, &album),
+                        Some((artist, album, false)) => {
+                            self.emit(&Event::album("selected", &artist, &album));
+                            Some(Menu::Song { artist, album })
+                        }
+                        None => None,
+                    }
+                }
+                Menu::ListAlbum { name } => {
+                    match self.select_list_album(&name, false, preselect_album)? {
+                        Some((artist, album, true)) => return self.queue_album(&artist, &album),
+                        Some((artist, album, false)) => {
+                            self.emit(&Event::album("selected", &artist, &album));
+                            Some(Menu::Song { artist, album })
+                        }
+                        None => None,
+                    }
+                }
+                Menu::Song { artist, album } => {
+                    if let Some((title, queue_mode)) =
+                        self.select_song(Some(&artist), Some(&album), preselect_index)?
+                    {
+                        return self.play_song(&artist, Some(&album), &title, queue_mode);
+                    }
+                    None
+                }
+            };
+
+            match next {
+                Some(next) => stack.push(next),
+                None => back_from = stack.pop(),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Runs until interrupted, reacting to MPD `idle` events. Reconnects if the
+    /// connection to MPD is lost.
+    async fn run_idle_task(
+        &mut self,
+        task: &mut IdleTask,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        loop {
+            let result = tokio::select! {
+                result = self.watch_events(task) => result,
+                _ = tokio::signal::ctrl_c() => return Ok(()),
+            };
+
+            if let Err(err) = result {
+                self.report_error(&format!("Lost connection to MPD: {}", err));
+                tokio::time::sleep(DAEMON_RECONNECT_DELAY).await;
+            }
+        }
+    }
+
+    async fn watch_events(
+        &mut self,
+        task: &mut IdleTask,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut idle = IdleConnection::connect().await?;
+        self.mpd = MpdClient::connect()?;
+        let result = match task {
+            IdleTask::Daemon(state) => {
+                // Pick up the song that is playing on startup without notifying
+                if state.current.is_none() {
+                    self.current_song_state()
+                        .map(|current| state.current = current)
+                } else {
+                    Ok(())
+                }
+            }
+            IdleTask::AutoDj(config) => self.top_up_queue(config),
+        };
+        if let Err(err) = result {
+            self.report_error(&format!("Could not handle startup: {}", err));
+        }
+
+        loop {
+            let changed = idle.idle(task.subsystems()).await?;
+
+            // MPD drops idle command connections after a while, so use a fresh one
+            self.mpd = MpdClient::connect()?;
+            for subsystem in changed {
+                // A failing handler shouldn't stop the watch; only losing
+                // MPD itself does
+                let result = match task {
+                    IdleTask::Daemon(state) => self.handle_daemon_event(&subsystem, state),
+                    IdleTask::AutoDj(config) => self.top_up_queue(config),
+                };
+                if let Err(err) = result {
+                    self.report_error(&format!("Could not handle {} event: {}", subsystem, err));
+                }
+            }
+        }
+    }
+
+    /// Appends songs from the auto-DJ source if fewer than `threshold` songs
+    /// remain after the current one.
+    fn top_up_queue(
+        &mut self,
+        config: &mut AutoDjConfig,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let status = self.mpd.get_status()?;
+        let length = status
+            .get("playlistlength")
+            .and_then(|length| length.parse::<usize>().ok())
+            .unwrap_or(0);
+        let current = status
+            .get("song")
+            .and_then(|song| song.parse::<usize>().ok());
+        let remaining = match current {
+            Some(position) => length.saturating_sub(position + 1),
+            None => length,
+        };
+        if remaining >= config.threshold {
+            return Ok(());
+        }
+
+        // Library picks are seeded per top-up, continuing the sequence of `--seed`
+        let mut options = config.options.clone();
+        options.seed = Some(config.rng.gen());
+        match &config.source {
+            AutoDjSource::Library => self.play_random_album(&options)?,
+            AutoDjSource::Genre(genre) => {
+                options.filter.genre = Some(genre.clone());
+                self.play_random_album(&options)?;
+            }
+            AutoDjSource::List(name) => {
+                let mut albums = self.load_list_albums(name)?;
+                albums.shuffle(&mut config.rng);
+                let mut queued = false;
+                for (artist, album) in albums {
+                    if self.mpd.first_album_file(&artist, &album)?.is_some() {
+                        self.queue_album(&artist, &album)?;
+                        queued = true;
+                        break;
+                    }
+                    self.report(&format!(
+                        "Skipping {} album not in the library: {} - {}",
+                        name, artist, album
+                    ));
+                }
+                if !queued {
+                    self.report(&format!("No albums of {} found in the library", name));
+                }
+            }
+            AutoDjSource::Playlist(name) => {
+                let mut files = self.mpd.list_playlist_files(name)?;
+                files.shuffle(&mut config.rng);
+                files.truncate(config.options.songs.unwrap_or(AUTODJ_PLAYLIST_BATCH));
+                if files.is_empty() {
+                    self.report(&format!("Playlist '{}' is empty", name));
+                } else {
+                    Command::new("mpc").arg("add").args(&files).output()?;
+                    let events = files
+                        .iter()
+                        .map(|file| Event {
+                            playlist: Some(name.clone()),
+                            ..Event::file("queued", file)
+                        })
+                        .collect();
+                    self.report_events(
+                        &format!("Queued from playlist {}:\n{}", name, files.join("\n")),
+                        events,
+                    );
+                }
+            }
+        }
+
+        // The queue ran out and playback stopped: continue with the new songs
+        if current.is_none() && status.get("state").map(String::as_str) == Some("stop") {
+            Command::new("mpc")
+                .args(["play", &(length + 1).to_string()])
+                .output()?;
+        }
+
+        Ok(())
+    }
+
+    fn handle_daemon_event(
+        &mut self,
+        subsystem: &str,
+        state: &mut DaemonState,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        match subsystem {
+            "player" => {
+                let current = self.current_song_state()?;
+                let file = current.as_ref().map(|current| &current.song.file);
+                if let Some(previous) = state.current.as_mut() {
+                    if Some(&previous.song.file) == file {
+                        // Same song, only remember the progress
+                        if let Some(current) = current {
+                            previous.elapsed = current.elapsed;
+                            previous.updated = current.updated;
+                            previous.playing = current.playing;
+                        }
+                        return Ok(());
+                    }
+                }
+
+                if let Some(previous) = state.current.take() {
+                    if let Err(err) = self.record_history(&previous) {
+                        self.report_error(&format!("Could not record history: {}", err));
+                    }
+                    if let Err(err) = self.check_graduation(&previous.song, state.graduate_after) {
+                        self.report_error(&format!(
+                            "Could not check quarantine graduation: {}",
+                            err
+                        ));
+                    }
+                    if previous.listened() >= PLAY_COUNT_THRESHOLD {
+                        if let Err(err) = self.count_play(&previous.song.file) {
+                            self.report_error(&format!("Could not update play count: {}", err));
+                        }
+                    }
+                }
+                if let Some(current) = &current {
+                    if current.playing {
+                        let song = &current.song;
+                        self.report_event(
+                            &format!("Playing:\n{}\n{}\n{}", song.artist, song.album, song.title),
+                            Event {
+                                file: Some(song.file.clone()),
+                                ..Event::song("playing", &song.artist, &song.album, &song.title)
+                            },
+                        );
+                        self.show_notification(&song.artist, &song.album, Some(&song.title));
+                    }
+                }
+                state.current = current;
+            }
+            "database" => {
+                // Covers of changed albums may have been added or replaced
+                match self.invalidate_covers() {
+                    Ok(0) => self.report("Database updated"),
+                    Ok(count) => self.report(&format!(
+                        "Database updated, dropped {} cached covers",
+                        count
+                    )),
+                    Err(err) => {
+                        self.report_error(&format!("Could not update the cover cache: {}", err))
+                    }
+                }
+                if state.import_new {
+                    if let Err(err) = self.list_import_new(QUARANTINE_LIST, None) {
+                        self.report_error(&format!("Could not import new albums: {}", err));
+                    }
+                }
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    fn current_song_state(&mut self) -> Result<Option<CurrentSong>, Box<dyn std::error::Error>> {
+        let Some(song) = self.mpd.current_song()? else {
+            return Ok(None);
+        };
+        let status = self.mpd.get_status()?;
+        let number = |key: &str| {
+            status
+                .get(key)
+                .and_then(|value| value.parse::<f64>().ok())
+                .unwrap_or(0.0)
+        };
+
+        Ok(Some(CurrentSong {
+            song,
+            started: unix_time(),
+            duration: number("duration"),
+            elapsed: number("elapsed"),
+            updated: std::time::Instant::now(),
+            playing: status.get("state").map(String::as_str) == Some("play"),
+        }))
+    }
+
+    /// Reads the listening history, oldest entry first. Lines that can't be parsed
+    /// are skipped with a warning.
+    fn load_history(&self) -> Result<Vec<HistoryEntry>, Box<dyn std::error::Error>> {
+        let path = history_path()?;
+        if !path.exists() {
+            return Ok(Vec::new());
+        }
+
+        let content = fs::read_to_string(&path)?;
+        let mut entries = Vec::new();
+        for (number, line) in content.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            // A line may be cut off if the daemon was killed while writing it
+            match serde_json::from_str(line) {
+                Ok(entry) => entries.push(entry),
+                Err(err) => self.report_error(&format!(
+                    "Skipping history entry {}:{}: {}",
+                    path.display(),
+                    number + 1,
+                    err
+                )),
+            }
+        }
+
+        Ok(entries)
+    }
+
+    fn record_history(&mut self, played: &CurrentSong) -> Result<(), Box<dyn std::error::Error>> {
+        let entry = HistoryEntry {
+            timestamp: played.started,
+            file: played.song.file.clone(),
+            artist: played.song.artist.clone(),
+            album: played.song.album.clone(),
+            title: played.song.title.clone(),
+            listened: played.listened(),
+        };
+
+        let path = history_path()?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        // Start on a new line if the last write was cut off
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(path)?;
+        let mut last = [0u8];
+        if file.seek(SeekFrom::End(-1)).is_ok()
+            && file.read_exact(&mut last).is_ok()
+            && last[0] != b'\n'
+        {
+            writeln!(file)?;
+        }
+        writeln!(file, "{}", serde_json::to_string(&entry)?)?;
+
+        Ok(())
+    }
+
+    /// Offers to graduate the album of a just played song if it is in quarantine
+    /// and that song completed its `listens_needed`th complete listen.
+    fn check_graduation(
+        &mut self,
+        song: &Track,
+        listens_needed: usize,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if listens_needed == 0
+            || !self
+                .load_list(QUARANTINE_LIST)?
+                .contains(&song.artist, &song.album)
+        {
+            return Ok(());
+        }
+
+        let files: Vec<String> = self
+            .mpd
+            .list_tracks(Some(&song.artist), Some(&song.album))?
+            .into_iter()
+            .map(|track| track.file)
+            .collect();
+        let history = self.load_history()?;
+        let listens = album_listens(&history, &files);
+        let previous_listens = album_listens(&history[..history.len().saturating_sub(1)], &files);
+        if listens < listens_needed || listens == previous_listens {
+            return Ok(());
+        }
+
+        // The prompt waits for an answer, so don't hold up the event loop
+        let (artist, album) = (song.artist.clone(), song.album.clone());
+        self.spawn_detached(move |selector| {
+            if let Err(err) = selector.prompt_graduation(&artist, &album, listens) {
+                selector.report_error(&format!(
+                    "Could not graduate {} - {}: {}",
+                    artist, album, err
+                ));
+            }
+        })
+    }
+
+    /// Asks whether to graduate, remove or keep a quarantine album, with a
+    /// notification if D-Bus is available and with rofi otherwise.
+    fn prompt_graduation(
+        &mut self,
+        artist: &str,
+        album: &str,
+        listens: usize,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let notification = Notification {
+            summary: "Graduate from quarantine?".to_string(),
+            message: format!("{}\n{}\nListened {} times", artist, album, listens),
+            image: self.cover_path(artist, album, None).ok().flatten(),
+            urgency: self.notification.urgency,
+            // Stay until answered, expiring would count as "keep"
+            timeout_ms: 0,
+            actions: GRADUATION_ACTIONS,
+        };
+        let notified = match self.notification.backend {
+            NotificationBackend::Auto | NotificationBackend::Dbus => {
+                notify_dbus(&notification, 0).ok()
+            }
+            _ => None,
+        };
+
+        let action = match notified {
+            Some((_, Some(receiver))) => receiver.recv().ok().flatten(),
+            _ => {
+                let labels: Vec<String> = GRADUATION_ACTIONS
+                    .iter()
+                    .map(|(_, label)| label.to_string())
+                    .collect();
+                let prompt = format!("Graduate {} - {}?", artist, album);
+                self.rofi_select_index(&labels, &[], &prompt, 0, false, &[])?
+                    .map(|(index, _)| GRADUATION_ACTIONS[index].0.to_string())
+            }
+        };
+
+        // MPD drops idle clients, so the connection may be gone after waiting
+        if matches!(action.as_deref(), Some("graduate" | "remove")) {
+            self.mpd = MpdClient::connect()?;
+        }
+        match action.as_deref() {
+            Some("graduate") => self.graduate_album(QUARANTINE_LIST, artist, album, true)?,
+            Some("remove") => self.remove_list_album(QUARANTINE_LIST, artist, album)?,
+            _ => self.report(&format!("Keeping in quarantine:\n{}\n{}", artist, album)),
+        }
+
+        Ok(())
+    }
+
+    /// Handles one invocation by rofi in script mode. The row info (`ROFI_INFO`)
+    /// of the selected entry encodes the menu level, so artist → album → song
+    /// navigation happens inside a single rofi window.
+    fn run_script_mode(
+        &mut self,
+        retv: i32,
+        info: Option<&str>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let queue_mode = retv == 10;
+        let fields: Vec<&str> = info.unwrap_or("").split('\t').collect();
+
+        match fields.as_slice() {
+            ["root", artist] => self.print_script_artists(Some(artist)),
+            ["artist", artist] => {
+                if queue_mode {
+                    Command::new("mpc")
+                        .args(["findadd", "albumartist", artist])
+                        .output()?;
+                    self.print_script_artists(Some(artist))
+                } else {
+                    self.print_script_albums(artist, None)
+                }
+            }
+            ["album", artist, album] => {
+                if queue_mode {
+                    self.queue_album(artist, album)?;
+                    self.print_script_albums(artist, Some(album))
+                } else {
+                    self.print_script_songs(artist, album, None)
+                }
+            }
+            ["back", artist, album] => self.print_script_albums(artist, Some(album)),
+            ["song", artist, album, title] => {
+                self.play_song(artist, Some(album), title, queue_mode)?;
+                if queue_mode {
+                    self.print_script_songs(artist, album, Some(title))
+                } else {
+                    // Printing no rows closes rofi
+                    Ok(())
+                }
+            }
+            _ => self.print_script_artists(None),
+        }
+    }
+
+    fn print_script_artists(
+        &mut self,
+        preselect: Option<&str>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut artists = self.get_artists()?;
+        artists.shuffle(&mut rand::thread_rng());
+        let current = self.mpd.current_song()?;
+
+        let rows: Vec<ScriptRow> = artists
+            .iter()
+            .map(|artist| ScriptRow {
+                display: artist.clone(),
+                info: format!("artist\t{}", artist),
+                icon: None,
+                active: current.as_ref().is_some_and(|song| &song.artist == artist),
+                urgent: false,
+            })
+            .collect();
+        let selected = preselect.and_then(|preselect| artists.iter().position(|a| a == preselect));
+
+        print_script_rows("Artist", "Ctrl+Enter: queue", &rows, selected);
+        Ok(())
+    }
+
+    fn print_script_albums(
+        &mut self,
+        artist: &str,
+        preselect: Option<&str>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut albums = self.get_albums(Some(artist))?;
+        albums.shuffle(&mut rand::thread_rng());
+        let current = self.mpd.current_song()?;
+        let quarantine = self.load_list_albums(QUARANTINE_LIST)?;
+        let mut icons = self.album_icons(&albums).into_iter();
+
+        let mut rows = vec![ScriptRow {
+            display: "..".to_string(),
+            info: format!("root\t{}", artist),
+            icon: None,
+            active: false,
+            urgent: false,
+        }];
+        rows.extend(albums.iter().map(|(artist, album)| {
+            ScriptRow {
+                display: album.clone(),
+                info: format!("album\t{}\t{}", artist, album),
+                icon: icons.next().flatten(),
+                active: current
+                    .as_ref()
+                    .is_some_and(|song| &song.artist == artist && &song.album == album),
+                urgent: quarantine.contains(&(artist.clone(), album.clone())),
+            }
+        }));
+        let selected = preselect
+            .and_then(|preselect| albums.iter().position(|(_, album)| album == preselect))
+            .map(|index| index + 1);
+
+        print_script_rows("Album", artist, &rows, selected);
+        Ok(())
+    }
+
+    fn print_script_songs(
+        &mut self,
+        artist: &str,
+        album: &str,
+        preselect: Option<&str>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let songs = self.get_songs(Some(artist), Some(album))?;
+        let current = self.mpd.current_song()?;
+
+        let mut rows = vec![ScriptRow {
+            display: "..".to_string(),
+            info: format!("back\t{}\t{}", artist, album),
+            icon: None,
+            active: false,
+            urgent: false,
+        }];
+        rows.extend(songs.iter().map(|title| ScriptRow {
+            display: title.clone(),
+            info: format!("song\t{}\t{}\t{}", artist, album, title),
+            icon: None,
+            active: current.as_ref().is_some_and(|song| {
+                song.artist == artist && song.album == album && &song.title == title
+            }),
+            urgent: false,
+        }));
+        let selected = preselect
+            .and_then(|preselect| songs.iter().position(|title| title == preselect))
+            .map(|index| index + 1);
+
+        print_script_rows("Song", &format!("{} — {}", artist, album), &rows, selected);
+        Ok(())
+    }
+
+    fn select_artist(
+        &mut self,
+        preselect: Option<&str>,
+    ) -> Result<Option<String>, Box<dyn std::error::Error>> {
+        let mut artists = self.get_artists()?;
+        if artists.is_empty() {
+            self.report("No artists found");
+            return Ok(None);
+        }
+
+        artists.shuffle(&mut rand::thread_rng());
+        let selected_row = preselect
+            .and_then(|preselect| artists.iter().position(|artist| artist == preselect))
+            .unwrap_or(0);
+        let (selected, _) = self.rofi_select(&artists, "Artist:", selected_row, false)?;
+        Ok(selected)
+    }
+
+    fn select_album(
+        &mut self,
+        artist: Option<&str>,
+        preselect: Option<(&str, &str)>,
+    ) -> Result<Option<AlbumSelection>, Box<dyn std::error::Error>> {
+        // One query gives both the albums and their stats
+        let tracks = self.mpd.list_tracks(artist, None)?;
+        let mut albums: Vec<(String, String)> = Vec::new();
+        let mut seen = HashSet::new();
+        for track in &tracks {
+            let album = (track.artist.clone(), track.album.clone());
+            if !album.0.is_empty() && !album.1.is_empty() && seen.insert(album.clone()) {
+                albums.push(album);
+            }
+        }
+        let stats = self.album_stats_of(&tracks, self.album_sort != AlbumSort::Random);
+        self.filter_albums_by_rating(&mut albums, &stats);
+        if albums.is_empty() {
+            self.report("No albums found");
+            return Ok(None);
+        }
+
+        let stats_of = |album: &(String, String)| stats.get(album).cloned().unwrap_or_default();
+        match self.album_sort {
+            AlbumSort::Random => albums.shuffle(&mut rand::thread_rng()),
+            AlbumSort::Plays => {
+                albums.sort_by_key(|album| std::cmp::Reverse(stats_of(album).plays))
+            }
+            AlbumSort::Recent => {
+                albums.sort_by_key(|album| std::cmp::Reverse(stats_of(album).last_played))
+            }
+        }
+
+        let selected_row = preselect
+            .and_then(|(artist, album)| albums.iter().position(|(a, b)| a == artist && b == album))
+            .unwrap_or(0);
+        let icons = self.album_icons(&albums);
+
+        let show_ratings = albums
+            .iter()
+            .any(|album| stats_of(album).rating().is_some());
+        let items: Vec<String> = albums
+            .iter()
+            .map(|key| {
+                let (album_artist, album) = key;
+                let album_stats = stats_of(key);
+                let mut columns = Vec::new();
+                if artist.is_none() {
+                    columns.push(album_artist.clone());
+                }
+                columns.push(album.clone());
+                if show_ratings {
+                    columns.push(album_stats.rating().map(format_stars).unwrap_or_default());
+                }
+                match self.album_sort {
+                    AlbumSort::Random => {}
+                    AlbumSort::Plays => columns.push(format_plays(album_stats.plays)),
+                    AlbumSort::Recent => columns.push(format_last_played(album_stats.last_played)),
+                }
+                columns.join("\t")
+            })
+            .collect();
+
+        let use_column_formatting =
+            artist.is_none() || show_ratings || self.album_sort != AlbumSort::Random;
+        if let Some((index, exit_code)) = self.rofi_select_index(
+            &items,
+            &icons,
+            "Album:",
+            selected_row,
+            use_column_formatting,
+            &[],
+        )? {
+            let (artist, album) = &albums[index];
+            return Ok(Some((artist.clone(), album.clone(), exit_code == 10)));
+        }
+
+        Ok(None)
+    }
+
+    fn select_song(
+        &mut self,
+        artist: Option<&str>,
+        album: Option<&str>,
+        preselect_index: usize,
+    ) -> Result<Option<(String, bool)>, Box<dyn std::error::Error>> {
+        let all_songs = artist.is_none() && album.is_none();
+        let mut tracks: Vec<Track> = self
+            .mpd
+            .list_tracks(artist, album)?
+            .into_iter()
+            .filter(|track| !track.title.is_empty())
+            .collect();
+
+        let ratings = self.ratings();
+        if let (Some(min_rating), true) = (self.min_rating, all_songs) {
+            tracks.retain(|track| ratings.get(&track.file).is_some_and(|&r| r >= min_rating));
+        }
+        if tracks.is_empty() {
+            self.report("No songs found");
+            return Ok(None);
+        }
+
+        // Shuffle songs if selecting from all songs
+        if all_songs {
+            tracks.shuffle(&mut rand::thread_rng());
+        }
+
+        let songs: Vec<String> = tracks
+            .iter()
+            .map(|track| {
+                if all_songs {
+                    format!("{}\t{}", track.artist, track.title)
+                } else {
+                    track.title.clone()
+                }
+            })
+            .collect();
+
+        let play_counts = self.play_counts();
+        let show_plays = tracks
+            .iter()
+            .any(|track| play_counts.contains_key(&track.file));
+        let show_ratings = tracks.iter().any(|track| ratings.contains_key(&track.file));
+        let items: Vec<String> = songs
+            .iter()
+            .zip(&tracks)
+            .map(|(song, track)| {
+                let mut columns = vec![song.clone()];
+                if show_ratings {
+                    let rating = ratings.get(&track.file);
+                    columns.push(rating.map(|&r| format_stars(r as f64)).unwrap_or_default());
+                }
+                if show_plays {
+                    let plays = play_counts.get(&track.file).copied().unwrap_or(0);
+                    columns.push(format_plays(plays));
+                }
+                columns.join("\t")
+            })
+            .collect();
+
+        let use_column_formatting = all_songs || show_plays || show_ratings;
+        if let Some((index, exit_code)) = self.rofi_select_index(
+            &items,
+            &[],
+            "Choose a song:",
+            preselect_index,
+            use_column_formatting,
+            &[],
+        )? {
+            return Ok(Some((songs[index].clone(), exit_code == 10)));
+        }
+
+        Ok(None)
+    }
+}
+
+fn parse_tracks(lines: &[String]) -> Vec<Track> {
+    let mut tracks: Vec<Track> = Vec::new();
+
+    for line in lines {
+        if let Some(value) = line.strip_prefix("file: ") {
+            tracks.push(Track {
+                artist: String::new(),
+                album: String::new(),
+                title: String::new(),
+                track: None,
+                file: value.to_string(),
+            });
+        } else if let Some(current_track) = tracks.last_mut() {
+            if let Some(value) = line.strip_prefix("AlbumArtist: ") {
+                current_track.artist = value.to_string();
+            } else if let Some(value) = line.strip_prefix("Album: ") {
+                current_track.album = value.to_string();
+            } else if let Some(value) = line.strip_prefix("Title: ") {
+                current_track.title = value.to_string();
+            } else if let Some(value) = line.strip_prefix("Track: ") {
+                current_track.track = Some(value.to_string());
+            }
+        }
+    }
+
+    tracks
+}
+
+/// Prints the mode options and rows of one rofi script mode menu.
+fn print_script_rows(prompt: &str, message: &str, rows: &[ScriptRow], selected: Option<usize>) {
+    let indices = |predicate: fn(&ScriptRow) -> bool| {
+        rows.iter()
+            .enumerate()
+            .filter(|(_, row)| predicate(row))
+            .map(|(index, _)| index.to_string())
+            .collect::<Vec<_>>()
+            .join(",")
+    };
+
+    println!("\0prompt\x1f{}", prompt);
+    println!("\0message\x1f{}", message);
+    println!("\0no-custom\x1ftrue");
+    println!("\0use-hot-keys\x1ftrue");
+    println!("\0active\x1f{}", indices(|row| row.active));
+    println!("\0urgent\x1f{}", indices(|row| row.urgent));
+    // rofi ignores new-selection unless the selection is kept
+    if let Some(selected) = selected {
+        println!("\0keep-selection\x1ftrue");
+        println!("\0new-selection\x1f{}", selected);
+    }
+
+    for row in rows {
+        match &row.icon {
+            Some(icon) => println!(
+                "{}\0info\x1f{}\x1ficon\x1f{}",
+                row.display,
+                row.info,
+                icon.display()
+            ),
+            None => println!("{}\0info\x1f{}", row.display, row.info),
+        }
+    }
+}
+
+/// Formats a 0 to 5 rating as stars, rounded to whole stars.
+fn format_stars(rating: f64) -> String {
+    let stars = (rating.round() as usize).min(5);
+    format!("{}{}", "★".repeat(stars), "☆".repeat(5 - stars))
+}
+
+fn format_plays(plays: u32) -> String {
+    match plays {
+        0 => String::new(),
+        1 => "1 play".to_string(),
+        plays => format!("{} plays", plays),
+    }
+}
+
+fn format_listens(listens: usize) -> String {
+    match listens {
+        0 => String::new(),
+        1 => "1 listen".to_string(),
+        listens => format!("{} listens", listens),
+    }
+}
+
+fn format_age(days: u64) -> String {
+    match days {
+        0 => "today".to_string(),
+        1 => "1 day".to_string(),
+        days => format!("{} days", days),
+    }
+}
+
+fn format_last_played(time: u64) -> String {
+    if time == 0 {
+        "never played".to_string()
+    } else {
+        format_date(time)
+    }
+}
+
+/// Formats a unix time as a `YYYY-MM-DD` date (UTC).
+fn format_date(time: u64) -> String {
+    let (year, month, day) = civil_from_days((time / 86400) as i64);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+/// Parses a `YYYY-MM-DD` date into the unix time of its start (UTC).
+/// Dates before 1970 and days the month doesn't have are rejected.
+fn parse_date(date: &str) -> Option<u64> {
+    let mut parts = date.trim().splitn(3, '-');
+    let year: i64 = parts.next()?.parse().ok()?;
+    let month: i64 = parts.next()?.parse().ok()?;
+    let day: i64 = parts.next()?.parse().ok()?;
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+
+    // Inverse of civil_from_days
+    let shifted_year = if month <= 2 { year - 1 } else { year };
+    let era = shifted_year.div_euclid(400);
+    let year_of_era = shifted_year.rem_euclid(400);
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146097 + day_of_era - 719468;
+
+    // Days past the end of the month (2024-02-31) land in the next month
+    if civil_from_days(days) != (year, month as u32, day as u32) {
+        return None;
+    }
+    u64::try_from(days).ok().map(|days| days * 86400)
+}
+
+/// Converts days since 1970-01-01 to a (year, month, day) date.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    // Howard Hinnant's algorithm
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+/// Returns a seeded random generator for reproducible picks, or a random one.
+fn random_generator(seed: Option<u64>) -> StdRng {
+    match seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy(),
+    }
+}
+
+/// Returns `$XDG_DATA_HOME/mpd_rofi`, falling back to `~/.local/share/mpd_rofi`.
+fn data_dir() -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let base = match std::env::var("XDG_DATA_HOME") {
+        Ok(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var("HOME")?).join(".local/share"),
+    };
+    Ok(base.join("mpd_rofi"))
+}
+
+fn history_path() -> Result<PathBuf, Box<dyn std::error::Error>> {
+    Ok(data_dir()?.join("history.jsonl"))
+}
+
+/// Counts how often every one of `files` has been listened to, i.e. the number
+/// of complete listens of an album.
+fn album_listens(history: &[HistoryEntry], files: &[String]) -> usize {
+    files
+        .iter()
+        .map(|file| {
+            history
+                .iter()
+                .filter(|entry| &entry.file == file && entry.listened >= PLAY_COUNT_THRESHOLD)
+                .count()
+        })
+        .min()
+        .unwrap_or(0)
+}
+
+fn unix_time() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+/// Returns `$XDG_CACHE_HOME/mpd_rofi`, falling back to `~/.cache/mpd_rofi`.
+fn cache_dir() -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let base = match std::env::var("XDG_CACHE_HOME") {
+        Ok(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var("HOME")?).join(".cache"),
+    };
+    Ok(base.join("mpd_rofi"))
+}
+
+/// Extensions of cached cover files, by detected image format
+const COVER_EXTENSIONS: [&str; 5] = ["jpg", "png", "gif", "webp", "img"];
+
+/// Covers fetched from MPD at most while building one menu
+const MAX_COVER_FETCHES: usize = 20;
+
+/// Looks up an album in the cover cache: `None` if it hasn't been fetched yet,
+/// `Some(None)` if MPD had no cover for it.
+fn cached_cover_path(
+    artist: &str,
+    album: &str,
+) -> Result<Option<Option<PathBuf>>, Box<dyn std::error::Error>> {
+    let cover_dir = cache_dir()?.join("covers");
+    let key = cover_key(artist, album);
+
+    for extension in COVER_EXTENSIONS {
+        let path = cover_dir.join(format!("{}.{}", key, extension));
+        if path.exists() {
+            return Ok(Some(Some(path)));
+        }
+    }
+    // Albums without cover are remembered so we don't ask MPD every time
+    if cover_dir.join(format!("{}.none", key)).exists() {
+        return Ok(Some(None));
+    }
+
+    Ok(None)
+}
+
+/// Returns the cover cache file name (without extension) of an album: a
+/// FNV-1a hash of artist and album, so any tag values map to a safe name.
+fn cover_key(artist: &str, album: &str) -> String {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in artist.bytes().chain([0]).chain(album.bytes()) {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    format!("{:016x}", hash)
+}
+
+const DAEMON_RECONNECT_DELAY: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// Albums not played for this many days (or never) get the highest weight
+const WEIGHT_MAX_DAYS: u64 = 365;
+
+/// Songs added at once from a stored playlist by the auto-DJ
+const AUTODJ_PLAYLIST_BATCH: usize = 10;
+
+/// Fraction of a song that has to be listened to for it to count as played
+const PLAY_COUNT_THRESHOLD: f64 = 0.5;
+
+const NOTIFICATION_TIMEOUT_MS: i32 = 3000;
+/// Buttons of the now playing notification
+const PLAYBACK_ACTIONS: &[(&str, &str)] = &[
+    ("next", "Next"),
+    ("queue-album", "Queue album"),
+    ("quarantine", "Quarantine"),
+];
+/// Buttons of the quarantine graduation prompt
+const GRADUATION_ACTIONS: &[(&str, &str)] = &[
+    ("graduate", "Graduate"),
+    ("remove", "Remove"),
+    ("keep", "Keep"),
+];
+/// How long to wait for a click on a notification action
+const NOTIFICATION_ACTION_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// Shows a notification through `org.freedesktop.Notifications` on the session
+/// bus (`DBUS_SESSION_BUS_ADDRESS`). Returns the notification id and, if the
+/// notification has actions, a receiver for the action the user invokes.
+fn notify_dbus(
+    notification: &Notification,
+    replaces_id: u32,
+) -> Result<(u32, Option<ActionReceiver>), Box<dyn std::error::Error>> {
+    let connection = zbus::blocking::Connection::session()?;
+    let proxy = zbus::blocking::Proxy::new(
+        &connection,
+        "org.freedesktop.Notifications",
+        "/org/freedesktop/Notifications",
+        "org.freedesktop.Notifications",
+    )?;
+
+    let actions: Vec<&str> = notification
+        .actions
+        .iter()
+        .flat_map(|(action, label)| [*action, *label])
+        .collect();
+    let mut hints: HashMap<&str, zbus::zvariant::Value> = HashMap::new();
+    hints.insert("urgency", (notification.urgency as u8).into());
+    let image = notification
+        .image
+        .as_ref()
+        .map(|path| path.display().to_string());
+    if let Some(image) = &image {
+        hints.insert("image-path", image.as_str().into());
+    }
+
+    // Subscribe before sending so a quick click isn't missed
+    let signals = if !notification.actions.is_empty() {
+        Some(proxy.receive_all_signals()?)
+    } else {
+        None
+    };
+
+    let id: u32 = proxy.call(
+        "Notify",
+        &(
+            "mpd_rofi",
+            replaces_id,
+            "",
+            notification.summary.as_str(),
+            notification.message.as_str(),
+            actions,
+            hints,
+            notification.timeout_ms,
+        ),
+    )?;
+
+    let Some(signals) = signals else {
+        return Ok((id, None));
+    };
+
+    let (sender, receiver) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        for message in signals {
+            let header = message.header();
+            let body = message.body();
+            match header.member().map(|member| member.as_str()) {
+                Some("ActionInvoked") => {
+                    if let Ok((signal_id, action)) = body.deserialize::<(u32, String)>() {
+                        if signal_id == id {
+                            let _ = sender.send(Some(action));
+                            return;
+                        }
+                    }
+                }
+                Some("NotificationClosed") => {
+                    if let Ok((signal_id, _)) = body.deserialize::<(u32, u32)>() {
+                        if signal_id == id {
+                            let _ = sender.send(None);
+                            return;
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+    });
+
+    Ok((id, Some(receiver)))
+}
+
+/// Shows a notification with the notify-send command. Actions are not supported.
+fn notify_send(
+    notification: &Notification,
+    replaces_id: u32,
+) -> Result<(u32, Option<ActionReceiver>), Box<dyn std::error::Error>> {
+    let mut cmd = Command::new("notify-send");
+    cmd.args(["-t", &notification.timeout_ms.to_string(), "-p"])
+        .args(["-u", notification.urgency.name()]);
+    if let Some(image) = &notification.image {
+        cmd.arg("-i").arg(image);
+    }
+    if replaces_id != 0 {
+        cmd.args(["-r", &replaces_id.to_string()]);
+    }
+
+    let output = cmd
+        .args([&notification.summary, &notification.message])
+        .output()?;
+    if !output.status.success() {
+        return Err(format!(
+            "notify-send failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+        .into());
+    }
+
+    let id = String::from_utf8_lossy(&output.stdout)
+        .trim()
+        .parse()
+        .unwrap_or(0);
+    Ok((id, None))
+}
+
+/// Returns the file holding the unix time of the last `import-new` into a list.
+fn last_import_path(name: &str) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    // Rejects invalid names
+    list_path(name)?;
+    Ok(data_dir()?
+        .join("lists")
+        .join(format!("{}.last-import", name)))
+}
+
+/// Returns `$XDG_DATA_HOME/mpd_rofi/lists/<name>.list`. The quarantine list is
+/// read from `~/music/quarantine` instead if that file exists.
+fn list_path(name: &str) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    if name.is_empty() || name.starts_with('.') || name.contains('/') {
+        return Err(format!("Invalid list name '{}'", name).into());
+    }
+    if name == QUARANTINE_LIST {
+        let legacy = PathBuf::from(std::env::var("HOME")?).join("music/quarantine");
+        if legacy.exists() {
+            return Ok(legacy);
+        }
+    }
+
+    Ok(data_dir()?.join("lists").join(format!("{}.list", name)))
+}
+
+/// Returns the file remembering the id of the last notification, so the next
+/// one can replace it.
+fn notification_id_path() -> Option<PathBuf> {
+    let dir = match std::env::var("XDG_RUNTIME_DIR") {
+        Ok(dir) if !dir.is_empty() => PathBuf::from(dir).join("mpd_rofi"),
+        _ => cache_dir().ok()?,
+    };
+    fs::create_dir_all(&dir).ok()?;
+    Some(dir.join("notification-id"))
+}
 
-            let (selected_display, queue_mode) =
-                self.rofi_select(&tab_separated_items, "Quarantine Album:", 0, true)?;
+/// Replaces `%field%` placeholders in `template` with the given values.
+fn format_template(template: &str, fields: &[(&str, &str)]) -> String {
+    let mut result = template.to_string();
+    for (name, value) in fields {
+        result = result.replace(&format!("%{}%", name), value);
+    }
+    result
+}
 
-            if let Some(selected) = selected_display {
-                if let Some(index) = tab_separated_items.iter().position(|x| x == &selected) {
-                    let (artist, album) = &albums[index];
-                    return Ok(Some((artist.clone(), album.clone(), queue_mode)));
-                }
-            }
+/// Quotes a value as a single argument of an MPD protocol command.
+fn quote_argument(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
 
-            Ok(None)
-        }
-    }
+/// Lowercases a tag and drops everything but letters and digits, for
+/// comparisons that ignore case and punctuation.
+fn normalize_tag(value: &str) -> String {
+    value
+        .chars()
+        .filter(|c| c.is_alphanumeric())
+        .flat_map(char::to_lowercase)
+        .collect()
+}
 
-    fn play_random_quarantine_album(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        if let Some((artist, album, _)) = self.select_quarantine_album(true)? {
-            Command::new("mpc").arg("clear").output()?;
-            Command::new("mpc")
-                .args(["findadd", "album", &album, "albumartist", &artist])
-                .output()?;
-            Command::new("mpc").arg("play").output()?;
+/// Levenshtein distance between two strings, counted in chars.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
 
-            println!("Playing random quarantine album:\n{}\n{}", artist, album);
-            self.show_notification(&artist, &album, None);
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != *cb);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
         }
-
-        Ok(())
+        previous = current;
     }
 
-    fn show_playlist(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        let playlist = self.mpd.get_playlist()?;
-        if playlist.is_empty() {
-            println!("Playlist is empty");
-            return Ok(());
-        }
+    previous[b.len()]
+}
 
-        let status = self.mpd.get_status()?;
-        let current_pos = status
-            .get("song")
-            .and_then(|s| s.parse::<usize>().ok())
-            .unwrap_or(0);
+/// Returns up to `limit` candidates whose key is close to `query`, closest first.
+fn near_matches<T: Clone>(
+    query: &str,
+    candidates: &[T],
+    key: impl Fn(&T) -> String,
+    limit: usize,
+) -> Vec<T> {
+    let query = normalize_tag(query);
+    let max_distance = (query.chars().count() / 3).max(2);
 
-        let playlist_items: Vec<String> = playlist
-            .iter()
-            .filter(|track| !track.artist.is_empty() && !track.title.is_empty())
-            .map(|track| {
-                let artist = &track.artist;
-                let title = &track.title;
+    let mut matches: Vec<(usize, &T)> = candidates
+        .iter()
+        .filter_map(|candidate| {
+            let key = normalize_tag(&key(candidate));
+            let contained = key.contains(&query) || query.contains(&key);
+            let distance = if contained && key.len().min(query.len()) >= 4 {
+                0
+            } else {
+                edit_distance(&query, &key)
+            };
+            (distance <= max_distance).then_some((distance, candidate))
+        })
+        .collect();
+    matches.sort_by_key(|(distance, _)| *distance);
 
-                let display_title = if let Some(track_num) = &track.track {
-                    let track_num = track_num.split('/').next().unwrap_or(track_num);
-                    if !track_num.is_empty() {
-                        format!("{:02} {}", track_num.parse::<u32>().unwrap_or(0), title)
-                    } else {
-                        title.to_string()
-                    }
-                } else {
-                    title.to_string()
-                };
+    matches
+        .into_iter()
+        .take(limit)
+        .map(|(_, candidate)| candidate.clone())
+        .collect()
+}
 
-                format!("{}\t{}", artist, display_title)
-            })
-            .collect();
+/// Fails unless `name` is one of `candidates`, suggesting the closest ones.
+fn require_match(
+    kind: &str,
+    name: &str,
+    candidates: &[String],
+) -> Result<(), Box<dyn std::error::Error>> {
+    if candidates.iter().any(|candidate| candidate == name) {
+        return Ok(());
+    }
 
-        let (selected_display, _) =
-            self.rofi_select(&playlist_items, "Playlist:", current_pos, true)?;
+    let suggestions = near_matches(name, candidates, |candidate| candidate.clone(), 3);
+    if suggestions.is_empty() {
+        return Err(format!("{} not found: '{}'", kind, name).into());
+    }
+    Err(format!(
+        "{} not found: '{}'. Did you mean '{}'?",
+        kind,
+        name,
+        suggestions.join("', '")
+    )
+    .into())
+}
 
-        if let Some(selected) = selected_display {
-            if let Some(index) = playlist_items.iter().position(|x| x == &selected) {
-                Command::new("mpc")
-                    .args(["play", &(index + 1).to_string()])
-                    .output()?;
+/// Escapes a value for use inside a quoted string of an MPD filter expression.
+fn escape_filter_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('\'', "\\'")
+        .replace('"', "\\\"")
+}
 
-                let track = &playlist[index];
-                let artist = if track.artist.is_empty() {
-                    "Unknown Artist"
-                } else {
-                    &track.artist
-                };
-                let album = if track.album.is_empty() {
-                    "Unknown Album"
-                } else {
-                    &track.album
-                };
-                let title = if track.title.is_empty() {
-                    "Unknown Title"
-                } else {
-                    &track.title
-                };
+/// Splits a search query into whitespace separated terms, keeping quoted
+/// parts (`album:"bar baz"`) together.
+fn split_search_terms(query: &str) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let mut terms = Vec::new();
+    let mut current = String::new();
+    let mut in_quotes = false;
+    let mut has_term = false;
 
-                self.show_notification(artist, album, Some(title));
+    for c in query.chars() {
+        match c {
+            '"' => {
+                in_quotes = !in_quotes;
+                has_term = true;
+            }
+            c if c.is_whitespace() && !in_quotes => {
+                if has_term {
+                    terms.push(std::mem::take(&mut current));
+                    has_term = false;
+                }
+            }
+            c => {
+                current.push(c);
+                has_term = true;
             }
         }
-
-        Ok(())
     }
 
-    fn select_artist(&mut self) -> Result<Option<String>, Box<dyn std::error::Error>> {
-        let mut artists = self.get_artists()?;
-        if artists.is_empty() {
-            println!("No artists found");
-            return Ok(None);
-        }
-
-        artists.shuffle(&mut rand::thread_rng());
-        let (selected, _) = self.rofi_select(&artists, "Artist:", 0, false)?;
-        Ok(selected)
+    if in_quotes {
+        return Err("Unterminated quote in search query".into());
+    }
+    if has_term {
+        terms.push(current);
     }
 
-    fn select_album(
-        &mut self,
-        artist: Option<&str>,
-    ) -> Result<Option<(String, String, bool)>, Box<dyn std::error::Error>> {
-        let mut albums = self.get_albums(artist)?;
-        if albums.is_empty() {
-            println!("No albums found");
-            return Ok(None);
-        }
+    Ok(terms)
+}
 
-        albums.shuffle(&mut rand::thread_rng());
+/// Translates a search query such as `artist:foo album:"bar baz" year:1990`
+/// into an MPD filter expression. Terms without a field prefix match any tag.
+fn parse_search_query(query: &str) -> Result<String, Box<dyn std::error::Error>> {
+    let mut terms = Vec::new();
 
-        if let Some(artist) = artist {
-            let album_names: Vec<String> = albums.iter().map(|(_, album)| album.clone()).collect();
-            let (selected_album, queue_mode) =
-                self.rofi_select(&album_names, "Album:", 0, false)?;
-            if let Some(album) = selected_album {
-                return Ok(Some((artist.to_string(), album, queue_mode)));
+    for term in split_search_terms(query)? {
+        let (tag, value) = match term.split_once(':') {
+            Some((field, value)) => {
+                let tag = match field.to_lowercase().as_str() {
+                    "artist" => "artist",
+                    "albumartist" => "albumartist",
+                    "album" => "album",
+                    "title" => "title",
+                    "genre" => "genre",
+                    "year" | "date" => "date",
+                    "composer" => "composer",
+                    "performer" => "performer",
+                    "track" => "track",
+                    "file" => "file",
+                    "any" => "any",
+                    _ => return Err(format!("Unknown search field: {}", field).into()),
+                };
+                (tag, value.to_string())
             }
-        } else {
-            let tab_separated_items: Vec<String> = albums
-                .iter()
-                .map(|(artist, album)| format!("{}\t{}", artist, album))
-                .collect();
+            None => ("any", term),
+        };
+        terms.push((tag, "contains", value));
+    }
 
-            let (selected_display, queue_mode) =
-                self.rofi_select(&tab_separated_items, "Album:", 0, true)?;
+    filter_expression(&terms).ok_or_else(|| "Empty search query".into())
+}
 
-            if let Some(selected) = selected_display {
-                if let Some(index) = tab_separated_items.iter().position(|x| x == &selected) {
-                    let (artist, album) = &albums[index];
-                    return Ok(Some((artist.clone(), album.clone(), queue_mode)));
-                }
-            }
-        }
+/// Combines `(tag, operator, value)` terms into an MPD filter expression
+/// matching songs that satisfy all of them. Returns `None` if there are no terms.
+fn filter_expression(terms: &[(&str, &str, String)]) -> Option<String> {
+    let mut expressions: Vec<String> = terms
+        .iter()
+        .filter(|(_, _, value)| !value.is_empty())
+        .map(|(tag, operator, value)| {
+            format!("({} {} '{}')", tag, operator, escape_filter_value(value))
+        })
+        .collect();
 
-        Ok(None)
+    match expressions.len() {
+        0 => None,
+        1 => Some(expressions.remove(0)),
+        _ => Some(format!("({})", expressions.join(" AND "))),
     }
+}
 
-    fn select_song(
-        &mut self,
-        artist: Option<&str>,
-        album: Option<&str>,
-        preselect_index: usize,
-    ) -> Result<Option<(String, bool)>, Box<dyn std::error::Error>> {
-        let mut songs = self.get_songs(artist, album)?;
-        if songs.is_empty() {
-            println!("No songs found");
-            return Ok(None);
-        }
+fn path_name(path: &str) -> &str {
+    path.rsplit('/').next().unwrap_or(path)
+}
 
-        // Shuffle songs if selecting from all songs
-        if artist.is_none() && album.is_none() {
-            songs.shuffle(&mut rand::thread_rng());
-        }
+fn parent_path(path: &str) -> &str {
+    path.rfind('/').map(|pos| &path[..pos]).unwrap_or("")
+}
 
-        let use_column_formatting = artist.is_none() && album.is_none();
-        let (selected, queue_mode) = self.rofi_select(
-            &songs,
-            "Choose a song:",
-            preselect_index,
-            use_column_formatting,
-        )?;
-        if let Some(song) = selected {
-            return Ok(Some((song, queue_mode)));
-        }
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // rofi sets ROFI_RETV when running us as a script mode (`-modi music:mpd_rofi`)
+    if let Ok(retv) = std::env::var("ROFI_RETV") {
+        let mut selector = MusicSelector::new(OutputMode::Quiet)?;
+        // rofi passes no options of ours, so covers are switched on by environment
+        selector.show_icons = std::env::var("MPD_ROFI_SHOW_ICONS").is_ok_and(|value| value == "1");
+        let info = std::env::var("ROFI_INFO").ok();
+        return selector.run_script_mode(retv.parse().unwrap_or(0), info.as_deref());
+    }
 
-        Ok(None)
+    let cli = Cli::parse();
+    let output = cli.output;
+    let result = run(cli);
+    if let (OutputMode::Json, Err(err)) = (output, &result) {
+        let event = Event {
+            message: Some(err.to_string()),
+            ..Event::new("error")
+        };
+        println!("{}", serde_json::to_string(&event)?);
+        std::process::exit(1);
     }
+
+    result
 }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let cli = Cli::parse();
-    let mut selector = MusicSelector::new()?;
+fn run(cli: Cli) -> Result<(), Box<dyn std::error::Error>> {
+    let mut selector = MusicSelector::new(cli.output)?;
+    selector.show_icons = cli.show_icons;
+    selector.album_sort = cli.sort;
+    selector.list_sort = cli.list_sort;
+    selector.notification.summary_template = cli.notify_summary.clone();
+    selector.notification.body_template = cli.notify_body.clone();
+    selector.notification.backend = cli.notify_backend;
+    selector.notification.urgency = cli.notify_urgency;
+    selector.notification.actions = cli.notify_actions;
 
     match cli.command {
         Some(Commands::Artist) => {
-            if let Some(artist) = selector.select_artist()? {
-                if let Some((artist, album, queue_mode)) = selector.select_album(Some(&artist))? {
-                    if queue_mode {
-                        Command::new("mpc")
-                            .args(["findadd", "album", &album, "albumartist", &artist])
-                            .output()?;
-                    } else if let Some((title, song_queue_mode)) =
-                        selector.select_song(Some(&artist), Some(&album), cli.preselect)?
-                    {
-                        selector.play_song(&artist, Some(&album), &title, song_queue_mode)?;
-                    }
-                }
-            }
+            selector.navigate(Menu::Artist, cli.preselect)?;
         }
 
-        Some(Commands::Album) => {
-            if let (Some(artist), Some(album)) = (&cli.artist, &cli.album) {
-                if let Some((title, queue_mode)) =
-                    selector.select_song(Some(artist), Some(album), cli.preselect)?
-                {
-                    selector.play_song(artist, Some(album), &title, queue_mode)?;
+        Some(Commands::Album { min_rating }) => {
+            selector.min_rating = min_rating;
+            let root = if let (Some(artist), Some(album)) = (&cli.artist, &cli.album) {
+                Menu::Song {
+                    artist: artist.clone(),
+                    album: album.clone(),
                 }
-            } else if let Some((artist, album, queue_mode)) =
-                selector.select_album(cli.artist.as_deref())?
-            {
-                if queue_mode {
-                    Command::new("mpc")
-                        .args(["findadd", "album", &album, "albumartist", &artist])
-                        .output()?;
-                } else if let Some((title, song_queue_mode)) =
-                    selector.select_song(Some(&artist), Some(&album), cli.preselect)?
-                {
-                    selector.play_song(&artist, Some(&album), &title, song_queue_mode)?;
+            } else {
+                Menu::Album {
+                    artist: cli.artist.clone(),
                 }
-            }
+            };
+            selector.navigate(root, cli.preselect)?;
         }
 
-        Some(Commands::Random) => {
-            selector.play_random_album()?;
+        Some(Commands::Random {
+            min_rating,
+            options,
+        }) => {
+            selector.min_rating = min_rating;
+            selector.play_random_album(&options)?;
         }
 
-        Some(Commands::Quarantine) => {
-            if let Some((artist, album, queue_mode)) = selector.select_quarantine_album(false)? {
-                if queue_mode {
-                    Command::new("mpc")
-                        .args(["findadd", "album", &album, "albumartist", &artist])
-                        .output()?;
-                } else if let Some((title, song_queue_mode)) =
-                    selector.select_song(Some(&artist), Some(&album), cli.preselect)?
-                {
-                    selector.play_song(&artist, Some(&album), &title, song_queue_mode)?;
-                }
-            }
+        Some(Commands::Quarantine { action }) => {
+            selector.run_list_action(QUARANTINE_LIST, action, cli.preselect)?;
         }
 
         Some(Commands::RandomQuarantine) => {
-            selector.play_random_quarantine_album()?;
+            selector.play_random_list_album(QUARANTINE_LIST)?;
+        }
+
+        Some(Commands::List { name, action }) => {
+            selector.run_list_action(&name, action, cli.preselect)?;
+        }
+
+        Some(Commands::RandomList { name }) => {
+            selector.play_random_list_album(&name)?;
         }
 
         Some(Commands::Playlist) => {
             selector.show_playlist()?;
         }
 
-        Some(Commands::Song) => {
+        Some(Commands::Browse) => {
+            selector.browse()?;
+        }
+
+        Some(Commands::Playlists { manage }) => {
+            if manage {
+                selector.manage_stored_playlists()?;
+            } else {
+                selector.load_stored_playlist()?;
+            }
+        }
+
+        Some(Commands::Rate { album }) => {
+            selector.rate_current(album)?;
+        }
+
+        Some(Commands::Daemon {
+            graduate_after,
+            import_new,
+        }) => {
+            let mut task = IdleTask::Daemon(DaemonState {
+                graduate_after,
+                import_new,
+                ..Default::default()
+            });
+            tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()?
+                .block_on(selector.run_idle_task(&mut task))?;
+        }
+
+        Some(Commands::AutoDj {
+            threshold,
+            source,
+            mut options,
+        }) => {
+            options.append = true;
+            let mut task = IdleTask::AutoDj(AutoDjConfig {
+                threshold,
+                source,
+                rng: Box::new(random_generator(options.seed)),
+                options,
+            });
+            tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()?
+                .block_on(selector.run_idle_task(&mut task))?;
+        }
+
+        Some(Commands::History { limit }) => {
+            selector.select_from_history(limit, cli.preselect)?;
+        }
+
+        Some(Commands::Play { target }) => {
+            selector.play_target(&target, false)?;
+        }
+
+        Some(Commands::Queue { target }) => {
+            selector.play_target(&target, true)?;
+        }
+
+        Some(Commands::Ls { target }) => {
+            selector.print_library(target)?;
+        }
+
+        Some(Commands::Search { query }) => {
+            selector.search_library(query.as_deref(), cli.preselect)?;
+        }
+
+        Some(Commands::Song { min_rating }) => {
+            selector.min_rating = min_rating;
             if let Some((song_result, queue_mode)) =
                 selector.select_song(None, None, cli.preselect)?
             {
@@ -783,19 +4930,361 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         }
 
         None => {
-            if let Some((artist, album, queue_mode)) = selector.select_album(None)? {
-                if queue_mode {
-                    Command::new("mpc")
-                        .args(["findadd", "album", &album, "albumartist", &artist])
-                        .output()?;
-                } else if let Some((title, song_queue_mode)) =
-                    selector.select_song(Some(&artist), Some(&album), cli.preselect)?
-                {
-                    selector.play_song(&artist, Some(&album), &title, song_queue_mode)?;
-                }
-            }
+            selector.navigate(Menu::Album { artist: None }, cli.preselect)?;
         }
     }
 
+    // Let notification clicks of one-shot commands still take effect
+    selector.wait_pending();
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::BufRead;
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    fn parse_entry(line: &str) -> ListEntry {
+        ListEntry::parse(line).unwrap().unwrap()
+    }
+
+    #[test]
+    fn parse_legacy_lines() {
+        let entry = parse_entry(r#""Radiohead", "OK Computer""#);
+        assert_eq!(entry, ListEntry::new("Radiohead", "OK Computer"));
+
+        let entry = parse_entry(r#""AC\DC", "Back in Black""#);
+        assert_eq!(entry, ListEntry::new("AC\\DC", "Back in Black"));
+    }
+
+    #[test]
+    fn parse_escapes() {
+        let entry = parse_entry(r#""The \"Band\"", "Back\\Slash""#);
+        assert_eq!(entry, ListEntry::new("The \"Band\"", "Back\\Slash"));
+    }
+
+    #[test]
+    fn parse_comments_and_blank_lines() {
+        assert_eq!(ListEntry::parse("").unwrap(), None);
+        assert_eq!(ListEntry::parse("   ").unwrap(), None);
+        assert_eq!(ListEntry::parse("# \"A\", \"B\"").unwrap(), None);
+
+        let entry = parse_entry(r#""A", "B"  # trailing comment"#);
+        assert_eq!(entry, ListEntry::new("A", "B"));
+    }
+
+    #[test]
+    fn parse_metadata_fields() {
+        let entry = parse_entry(
+            r#""A", "B", added="2024-01-02", note="good, \"really\"", source="bandcamp", mbid="123""#,
+        );
+        assert_eq!(entry.artist, "A");
+        assert_eq!(entry.album, "B");
+        assert_eq!(entry.added.as_deref(), Some("2024-01-02"));
+        assert_eq!(entry.note.as_deref(), Some("good, \"really\""));
+        assert_eq!(entry.source.as_deref(), Some("bandcamp"));
+        assert_eq!(entry.mbid.as_deref(), Some("123"));
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert!(ListEntry::parse(r#""A""#).is_err());
+        assert!(ListEntry::parse(r#""A", "B", "C""#).is_err());
+        assert!(ListEntry::parse(r#""A", "B"#).is_err());
+        assert!(ListEntry::parse(r#""A" "B""#).is_err());
+        assert!(ListEntry::parse(r#""A", "B", rating="5""#).is_err());
+        assert!(ListEntry::parse(r#""A", "B", added"x""#).is_err());
+        assert!(ListEntry::parse("A, B").is_err());
+    }
+
+    #[test]
+    fn format_round_trip() {
+        let entries = [
+            ListEntry::new("Radiohead", "OK Computer"),
+            ListEntry::new("AC\\DC", "Back in \"Black\""),
+            ListEntry {
+                added: Some("2024-01-02".to_string()),
+                note: Some("a, b # c".to_string()),
+                source: Some("import".to_string()),
+                mbid: Some("abc".to_string()),
+                ..ListEntry::new("A", "B")
+            },
+        ];
+        for entry in entries {
+            assert_eq!(parse_entry(&entry.format()), entry);
+        }
+    }
+
+    #[test]
+    fn load_reports_error_line_numbers() {
+        let dir = std::env::temp_dir().join(format!("mpd_rofi-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("test.list");
+        fs::write(
+            &path,
+            "# albums\n\"A\", \"B\"\n\"C\"\n\n\"D\", \"E\", x=\"1\"\n",
+        )
+        .unwrap();
+
+        let list = AlbumList::load(path.clone()).unwrap();
+        let _ = fs::remove_dir_all(&dir);
+
+        assert_eq!(list.albums(), vec![("A".to_string(), "B".to_string())]);
+        assert_eq!(list.errors.len(), 2);
+        assert!(list.errors[0].starts_with(&format!("{}:3: ", path.display())));
+        assert!(list.errors[1].starts_with(&format!("{}:5: ", path.display())));
+    }
+
+    #[test]
+    fn parse_date_known_dates() {
+        assert_eq!(parse_date("1970-01-01"), Some(0));
+        assert_eq!(parse_date("2000-03-01"), Some(951868800));
+        assert_eq!(parse_date("2024-02-29"), Some(1709164800));
+        assert_eq!(parse_date(" 2024-12-31 "), Some(1735603200));
+    }
+
+    #[test]
+    fn parse_date_rejects_invalid_dates() {
+        for date in [
+            "2024-02-30",
+            "2024-02-31",
+            "2023-02-29",
+            "2100-02-29",
+            "2024-04-31",
+            "2024-13-01",
+            "2024-00-10",
+            "2024-01-00",
+            "1969-12-31",
+            "2024-01",
+            "2024/01/01",
+            "",
+        ] {
+            assert_eq!(parse_date(date), None, "{}", date);
+        }
+    }
+
+    #[test]
+    fn date_round_trip() {
+        // Every day from 1970 to beyond 2100, across all leap year rules
+        for days in 0..50_000u64 {
+            let time = days * 86400;
+            let date = format_date(time);
+            assert_eq!(parse_date(&date), Some(time), "{}", date);
+        }
+        assert_eq!(format_date(parse_date("2000-02-29").unwrap()), "2000-02-29");
+        assert_eq!(format_date(parse_date("2024-02-29").unwrap()), "2024-02-29");
+    }
+
+    #[test]
+    fn split_search_terms_keeps_quoted_parts() {
+        assert_eq!(
+            split_search_terms(r#"artist:foo  album:"bar baz" qux"#).unwrap(),
+            vec!["artist:foo", "album:bar baz", "qux"]
+        );
+        assert_eq!(split_search_terms(r#""" a"#).unwrap(), vec!["", "a"]);
+        assert!(split_search_terms("").unwrap().is_empty());
+        assert!(split_search_terms(r#"album:"open"#).is_err());
+    }
+
+    #[test]
+    fn parse_search_query_builds_filters() {
+        assert_eq!(parse_search_query("foo").unwrap(), "(any contains 'foo')");
+        assert_eq!(
+            parse_search_query(r#"Artist:foo album:"bar baz" year:1990"#).unwrap(),
+            "((artist contains 'foo') AND (album contains 'bar baz') AND (date contains '1990'))"
+        );
+        assert_eq!(
+            parse_search_query(r#"title:"it's \ here""#).unwrap(),
+            r#"(title contains 'it\'s \\ here')"#
+        );
+        // Empty values don't restrict the search
+        assert_eq!(
+            parse_search_query("album: foo").unwrap(),
+            "(any contains 'foo')"
+        );
+    }
+
+    #[test]
+    fn parse_search_query_errors() {
+        assert!(parse_search_query("").is_err());
+        assert!(parse_search_query("album:").is_err());
+        assert!(parse_search_query("rating:5").is_err());
+        assert!(parse_search_query(r#"album:"foo"#).is_err());
+    }
+
+    #[test]
+    fn edit_distance_counts_chars() {
+        assert_eq!(edit_distance("", ""), 0);
+        assert_eq!(edit_distance("abc", ""), 3);
+        assert_eq!(edit_distance("", "abc"), 3);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("flaw", "lawn"), 2);
+        assert_eq!(edit_distance("björk", "bjork"), 1);
+        assert_eq!(edit_distance("same", "same"), 0);
+    }
+
+    #[test]
+    fn normalize_tag_ignores_case_and_punctuation() {
+        assert_eq!(normalize_tag("AC/DC"), "acdc");
+        assert_eq!(normalize_tag("Sigur Rós"), "sigurrós");
+        assert_eq!(normalize_tag(" -- "), "");
+    }
+
+    #[test]
+    fn near_matches_closest_first() {
+        let artists = [
+            "Radiohead".to_string(),
+            "Radio Dept.".to_string(),
+            "Portishead".to_string(),
+            "Massive Attack".to_string(),
+        ];
+        let key = |artist: &String| artist.clone();
+
+        assert_eq!(
+            near_matches("radiohed", &artists, key, 3),
+            vec!["Radiohead".to_string()]
+        );
+        // A key containing the query counts as distance 0
+        assert_eq!(
+            near_matches("Massive", &artists, key, 3),
+            vec!["Massive Attack".to_string()]
+        );
+        assert_eq!(
+            near_matches("radio", &artists, key, 3),
+            vec!["Radiohead".to_string(), "Radio Dept.".to_string()]
+        );
+        assert_eq!(
+            near_matches("radio", &artists, key, 1),
+            vec!["Radiohead".to_string()]
+        );
+        assert!(near_matches("Beatles", &artists, key, 3).is_empty());
+    }
+
+    /// What the fake notification server received in a `Notify` call.
+    #[derive(Debug, Clone)]
+    struct Notified {
+        replaces_id: u32,
+        summary: String,
+        actions: Vec<String>,
+        urgency: Option<u8>,
+        image_path: Option<String>,
+        expire_timeout: i32,
+    }
+
+    struct FakeNotifications {
+        received: Arc<Mutex<Vec<Notified>>>,
+    }
+
+    #[zbus::interface(name = "org.freedesktop.Notifications")]
+    impl FakeNotifications {
+        #[allow(clippy::too_many_arguments)]
+        fn notify(
+            &self,
+            _app_name: String,
+            replaces_id: u32,
+            _app_icon: String,
+            summary: String,
+            _body: String,
+            actions: Vec<String>,
+            hints: HashMap<String, zbus::zvariant::OwnedValue>,
+            expire_timeout: i32,
+        ) -> u32 {
+            let notified = Notified {
+                replaces_id,
+                summary,
+                actions,
+                urgency: hints.get("urgency").and_then(|v| u8::try_from(v).ok()),
+                image_path: hints
+                    .get("image-path")
+                    .and_then(|v| String::try_from(v.clone()).ok()),
+                expire_timeout,
+            };
+            self.received.lock().unwrap().push(notified);
+            if replaces_id != 0 {
+                replaces_id
+            } else {
+                42
+            }
+        }
+    }
+
+    /// Starts a private session bus, returning the daemon and its address.
+    fn private_bus() -> (std::process::Child, String) {
+        let mut daemon = Command::new("dbus-daemon")
+            .args(["--session", "--nofork", "--print-address"])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("dbus-daemon is needed to test notifications");
+        let mut address = String::new();
+        std::io::BufReader::new(daemon.stdout.take().unwrap())
+            .read_line(&mut address)
+            .unwrap();
+        (daemon, address.trim().to_string())
+    }
+
+    #[test]
+    fn notify_dbus_round_trip() {
+        let (mut daemon, address) = private_bus();
+        std::env::set_var("DBUS_SESSION_BUS_ADDRESS", &address);
+
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let server = zbus::blocking::connection::Builder::address(address.as_str())
+            .unwrap()
+            .name("org.freedesktop.Notifications")
+            .unwrap()
+            .serve_at(
+                "/org/freedesktop/Notifications",
+                FakeNotifications {
+                    received: received.clone(),
+                },
+            )
+            .unwrap()
+            .build()
+            .unwrap();
+
+        let notification = Notification {
+            summary: "Now Playing".to_string(),
+            message: "Artist\nAlbum".to_string(),
+            image: Some(PathBuf::from("/tmp/cover.jpg")),
+            urgency: NotificationUrgency::Critical,
+            timeout_ms: 0,
+            actions: PLAYBACK_ACTIONS,
+        };
+        let (id, receiver) = notify_dbus(&notification, 7).unwrap();
+        assert_eq!(id, 7);
+
+        server
+            .emit_signal(
+                None::<()>,
+                "/org/freedesktop/Notifications",
+                "org.freedesktop.Notifications",
+                "ActionInvoked",
+                &(id, "queue-album"),
+            )
+            .unwrap();
+        let action = receiver
+            .unwrap()
+            .recv_timeout(Duration::from_secs(5))
+            .unwrap();
+        assert_eq!(action.as_deref(), Some("queue-album"));
+
+        let received = received.lock().unwrap().clone();
+        let _ = daemon.kill();
+        let _ = daemon.wait();
+        assert_eq!(received.len(), 1);
+        let notified = &received[0];
+        assert_eq!(notified.replaces_id, 7);
+        assert_eq!(notified.summary, "Now Playing");
+        assert_eq!(notified.urgency, Some(NotificationUrgency::Critical as u8));
+        assert_eq!(notified.image_path.as_deref(), Some("/tmp/cover.jpg"));
+        assert_eq!(notified.expire_timeout, 0);
+        let expected: Vec<String> = PLAYBACK_ACTIONS
+            .iter()
+            .flat_map(|(action, label)| [action.to_string(), label.to_string()])
+            .collect();
+        assert_eq!(notified.actions, expected);
+    }
+}